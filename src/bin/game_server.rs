@@ -0,0 +1,149 @@
+//! Forwards GGRS packets between players who'd rather not send them to each
+//! other directly, see `game_server` in the game. Players reach it like any
+//! other peer, through the signalling relays it's given:
+//!
+//! ```text
+//! cargo run --release --bin game_server --features game-server -- wss://nostr.lu.ke
+//! ```
+//!
+//! The key comes from `FIGHTGAME_SERVER_NSEC`, or a new one is made up and
+//! printed so it can be kept. Hosts enter the npub in the lobby.
+//!
+//! It only passes inputs along, each player still runs the simulation. The
+//! players still need a direct connection for everything else, so it's no way
+//! around NATs that keep them from reaching each other.
+
+#[path = "../forwarding.rs"]
+mod forwarding;
+// the lobby and chat channels go straight between the players, never through here
+#[allow(dead_code)]
+#[path = "../socket_builder.rs"]
+mod socket_builder;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::prelude::{FromBech32, ToBech32};
+use nostr_sdk::secp256k1::SecretKey;
+use nostr_sdk::Keys;
+use tokio::task::LocalSet;
+
+use socket_builder::{socket_builder, GGRS_CHANNEL};
+
+const DEFAULT_RELAY: &str = "wss://nostr.lu.ke";
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Who sends to whom. Packets only go through once the player they're for
+/// sent some back, so nobody gets flooded by strangers.
+#[derive(Default)]
+struct Router {
+    sending_to: HashMap<PeerId, PeerId>,
+}
+
+impl Router {
+    fn route(&mut self, from: PeerId, packet: &[u8]) -> Option<(PeerId, Box<[u8]>)> {
+        let (to, payload) = forwarding::unwrap(packet)?;
+        let to = PeerId(to);
+        self.sending_to.insert(from, to);
+        (self.sending_to.get(&to) == Some(&from)).then(|| (to, forwarding::wrap(from.0, payload)))
+    }
+
+    fn forget(&mut self, peer: PeerId) {
+        self.sending_to.remove(&peer);
+    }
+}
+
+fn keys() -> Keys {
+    if let Ok(nsec) = std::env::var("FIGHTGAME_SERVER_NSEC") {
+        let secret_key =
+            SecretKey::from_bech32(nsec.trim()).expect("FIGHTGAME_SERVER_NSEC is not an nsec");
+        return Keys::new(secret_key);
+    }
+    let keys = Keys::generate();
+    let nsec = keys.secret_key().unwrap().to_bech32().unwrap();
+    println!("no FIGHTGAME_SERVER_NSEC set, using a new key: {nsec}");
+    keys
+}
+
+/// Serves the players signalling through `relay`
+async fn serve(relay: String, keys: Keys) {
+    let (mut socket, message_loop) = socket_builder(relay.clone(), keys).build();
+    tokio::task::spawn_local(message_loop);
+    println!("listening for players on {relay}");
+
+    let mut router = Router::default();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        poll.tick().await;
+        for (peer, state) in socket.update_peers() {
+            match state {
+                PeerState::Connected => println!("{relay}: {peer:?} connected"),
+                PeerState::Disconnected => {
+                    println!("{relay}: {peer:?} left");
+                    router.forget(peer);
+                }
+            }
+        }
+        let channel = socket.channel(GGRS_CHANNEL);
+        for (from, packet) in channel.receive() {
+            if let Some((to, packet)) = router.route(from, &packet) {
+                channel.send(packet, to);
+            }
+        }
+    }
+}
+
+fn main() {
+    let relays: Vec<String> = std::env::args().skip(1).collect();
+    let relays = if relays.is_empty() {
+        vec![DEFAULT_RELAY.to_string()]
+    } else {
+        relays
+    };
+    let keys = keys();
+    println!(
+        "game server npub: {}",
+        keys.public_key().to_bech32().unwrap()
+    );
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("starting the async runtime");
+    let tasks = LocalSet::new();
+    for relay in relays {
+        tasks.spawn_local(serve(relay, keys.clone()));
+    }
+    runtime.block_on(tasks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_pairs_get_forwarded() {
+        let (a, b, stranger) = (
+            PeerId(Keys::generate().public_key()),
+            PeerId(Keys::generate().public_key()),
+            PeerId(Keys::generate().public_key()),
+        );
+        let mut router = Router::default();
+
+        // b hasn't said anything yet
+        assert_eq!(router.route(a, &forwarding::wrap(b.0, b"sync")), None);
+        let (to, packet) = router
+            .route(b, &forwarding::wrap(a.0, b"sync"))
+            .expect("both sides asked for each other");
+        assert_eq!(to, a);
+        assert_eq!(forwarding::unwrap(&packet), Some((b.0, &b"sync"[..])));
+        assert!(router.route(a, &forwarding::wrap(b.0, b"input")).is_some());
+
+        // a is busy with b
+        assert_eq!(
+            router.route(stranger, &forwarding::wrap(a.0, b"spam")),
+            None
+        );
+    }
+}
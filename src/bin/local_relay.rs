@@ -0,0 +1,30 @@
+//! Stand-in for the public relays, for playing offline or on a LAN and for
+//! development. Listens on `127.0.0.1:7447` unless given another address:
+//!
+//! ```text
+//! cargo run --bin local_relay --features local-relay -- 0.0.0.0:7447
+//! ```
+//!
+//! Then pick "Local relay" in the game's relay settings, or add
+//! `ws://<this machine>:7447` on the other computers of the LAN.
+
+#[path = "../local_relay.rs"]
+mod local_relay;
+
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() {
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| local_relay::DEFAULT_ADDR.to_string());
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("can't listen on {addr}: {e}");
+            std::process::exit(1);
+        }
+    };
+    println!("local relay listening on ws://{addr}");
+    local_relay::serve(listener).await;
+}
@@ -0,0 +1,301 @@
+//! The game list in the menu. Listings stream in from every relay, get merged
+//! per host and drop out again once they start, close or expire.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use bevy_egui::egui;
+use nostr_sdk::{Client, RelayPoolNotification, Timestamp};
+
+use crate::{
+    components::Nostr,
+    lobby::{listing_filter, parse_listing, ListingStatus, GAME_VERSION, LISTING_TTL, MODES},
+    profiles::{profile_label, AvatarTextures, Profiles},
+    relays::{self, RelayHealth},
+    tasks::spawn,
+    Game, GamesList, SearchGames,
+};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The running listing subscription, replaced on refresh
+#[derive(Resource, Default, Clone)]
+pub struct LobbySubscription(Arc<Mutex<Subscribed>>);
+
+#[derive(Default)]
+struct Subscribed {
+    /// Bumped on every refresh, tasks of an older one stop writing
+    generation: u64,
+    client: Option<Client>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GameSort {
+    #[default]
+    Newest,
+    /// By how fast the host's signalling relay answers us. Not the ping to
+    /// the host, that needs a connection to them.
+    RelayLatency,
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct BrowserFilter {
+    /// `None` shows every mode
+    pub mode: Option<String>,
+    pub sort: GameSort,
+    /// Other versions likely can't play with us
+    pub hide_other_versions: bool,
+}
+
+impl Default for BrowserFilter {
+    fn default() -> Self {
+        Self {
+            mode: None,
+            sort: GameSort::default(),
+            hide_other_versions: true,
+        }
+    }
+}
+
+/// Everything `menu` needs for the game list
+#[derive(SystemParam)]
+pub struct Browser<'w> {
+    pub games: Res<'w, GamesList>,
+    pub filter: ResMut<'w, BrowserFilter>,
+    pub search_games: ResMut<'w, SearchGames>,
+    pub subscription: Res<'w, LobbySubscription>,
+    pub health: Res<'w, RelayHealth>,
+}
+
+/// Starts over with an empty list and a fresh subscription
+pub fn subscribe_listings(nostr: &Nostr, games: &GamesList, subscription: &LobbySubscription) {
+    games.0.lock().unwrap().clear();
+    let generation = {
+        let mut subscribed = subscription.0.lock().unwrap();
+        subscribed.generation += 1;
+        if let Some(client) = subscribed.client.take() {
+            spawn(async move {
+                client.shutdown().await.ok();
+            });
+        }
+        subscribed.generation
+    };
+
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+    let games = games.0.clone();
+    let subscription = subscription.0.clone();
+    info!("connecting to nostr relays: {:?}", relays);
+    spawn(async move {
+        let client = relays::connect(&keys, &relays).await;
+        {
+            let mut subscribed = subscription.lock().unwrap();
+            // refreshed again while we were connecting
+            if subscribed.generation != generation {
+                spawn(async move {
+                    client.shutdown().await.ok();
+                });
+                return;
+            }
+            subscribed.client = Some(client.clone());
+        }
+        client.subscribe(vec![listing_filter()]).await;
+
+        client
+            .handle_notifications(move |notification| {
+                let games = games.clone();
+                let subscription = subscription.clone();
+                async move {
+                    let current = subscription.lock().unwrap().generation == generation;
+                    if let (RelayPoolNotification::Event(url, event), true) =
+                        (notification, current)
+                    {
+                        if let Some(game) = parse_listing(&event, url.as_str()) {
+                            insert_listing(&mut games.lock().unwrap(), game);
+                        }
+                    }
+                    Ok(())
+                }
+            })
+            .await
+            .ok();
+    });
+}
+
+/// One listing per host, the newest one whatever it says. Started and closed
+/// ones are kept until they expire, so an older copy from a slower relay can't
+/// bring the game back. Only open ones are shown.
+fn insert_listing(games: &mut Vec<Game>, game: Game) {
+    let Some(listed) = games
+        .iter_mut()
+        .find(|listed| listed.created_by == game.created_by)
+    else {
+        games.push(game);
+        return;
+    };
+    // every relay we're on sends its copy. Within the same second the lowest
+    // id wins, like relays do for replaceable events.
+    let newer = game.created_at > listed.created_at
+        || (game.created_at == listed.created_at && game.id.to_hex() < listed.id.to_hex());
+    if newer {
+        *listed = game;
+    }
+}
+
+fn stale(game: &Game, now: u64) -> bool {
+    match game.expires_at {
+        Some(expires_at) => expires_at <= now,
+        None => game.created_at + LISTING_TTL.as_secs() <= now,
+    }
+}
+
+pub fn prune_games(games: Res<GamesList>, mut last_prune: Local<Option<Instant>>) {
+    if last_prune.map_or(false, |prune| prune.elapsed() < PRUNE_INTERVAL) {
+        return;
+    }
+    *last_prune = Some(Instant::now());
+    let now = Timestamp::now().as_u64();
+    games.0.lock().unwrap().retain(|game| !stale(game, now));
+}
+
+/// Comes back to a fresh list after a match
+pub fn refresh_games(mut search_games: ResMut<SearchGames>) {
+    search_games.search = true;
+}
+
+/// Draws the filter bar and the list, returns the game that was clicked
+pub fn game_browser(
+    ui: &mut egui::Ui,
+    browser: &mut Browser,
+    profiles: &mut Profiles,
+    avatars: &AvatarTextures,
+) -> Option<Game> {
+    let Browser {
+        games,
+        filter,
+        search_games,
+        health,
+        ..
+    } = browser;
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_label("Mode")
+            .selected_text(filter.mode.as_deref().unwrap_or("all"))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut filter.mode, None, "all");
+                for mode in MODES {
+                    ui.selectable_value(&mut filter.mode, Some(mode.to_string()), *mode);
+                }
+            });
+        ui.label("Sort:");
+        ui.radio_value(&mut filter.sort, GameSort::Newest, "newest");
+        ui.radio_value(&mut filter.sort, GameSort::RelayLatency, "relay latency");
+        if ui.small_button("Refresh").clicked() {
+            search_games.search = true;
+        }
+    });
+    ui.checkbox(&mut filter.hide_other_versions, "Hide other versions");
+
+    let mut listed: Vec<Game> = games
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|game| game.listing.status == ListingStatus::Open)
+        .filter(|game| {
+            filter
+                .mode
+                .as_ref()
+                .map_or(true, |mode| game.listing.mode == *mode)
+        })
+        .filter(|game| !filter.hide_other_versions || game.listing.version == GAME_VERSION)
+        .cloned()
+        .collect();
+    match filter.sort {
+        GameSort::Newest => listed.sort_by_key(|game| std::cmp::Reverse(game.created_at)),
+        // unknown relays go last
+        GameSort::RelayLatency => {
+            listed.sort_by_key(|game| health.latency(&game.relay).unwrap_or(Duration::MAX))
+        }
+    }
+
+    ui.separator();
+    if listed.is_empty() {
+        ui.label("No games found, please wait or create a game.");
+    }
+
+    let now = Timestamp::now().as_u64();
+    let mut joined = None;
+    for game in listed {
+        ui.horizontal(|ui| {
+            if ui
+                .button(format!("GAME NAME: {}", game.listing.name))
+                .clicked()
+            {
+                joined = Some(game.clone());
+            }
+            ui.weak(format!(
+                "{} · {} · {}",
+                game.listing.mode, game.listing.map, game.listing.region
+            ));
+            ui.label("CREATED BY:");
+            match game.host() {
+                Some(host) => profile_label(ui, profiles, avatars, host),
+                None => {
+                    ui.label(&game.created_by);
+                }
+            }
+            let age = now.saturating_sub(game.created_at);
+            ui.weak(format!("{}m ago", age / 60));
+            if let Some(latency) = health.latency(&game.relay) {
+                ui.weak(format!("relay {} ms", latency.as_millis()));
+            }
+        });
+    }
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::EventId;
+
+    use super::*;
+    use crate::lobby::Listing;
+
+    fn game(id: u8, created_at: u64, status: ListingStatus) -> Game {
+        let mut listing = Listing::new("game".to_string());
+        listing.status = status;
+        Game {
+            listing,
+            created_by: "npub1host".to_string(),
+            peer: "npub1host".to_string(),
+            relay: "wss://relay.example.com".to_string(),
+            id: EventId::from_hex(&format!("{id:02x}").repeat(32)).unwrap(),
+            d: "game".to_string(),
+            created_at,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn closed_games_stay_closed() {
+        let mut games = Vec::new();
+        insert_listing(&mut games, game(1, 10, ListingStatus::Open));
+        insert_listing(&mut games, game(2, 20, ListingStatus::Closed));
+        // a slower relay's copy of the open one
+        insert_listing(&mut games, game(1, 10, ListingStatus::Open));
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].listing.status, ListingStatus::Closed);
+    }
+
+    #[test]
+    fn same_second_goes_to_the_lowest_id() {
+        let mut games = Vec::new();
+        insert_listing(&mut games, game(9, 10, ListingStatus::Open));
+        insert_listing(&mut games, game(3, 10, ListingStatus::Started));
+        insert_listing(&mut games, game(5, 10, ListingStatus::Open));
+        assert_eq!(games[0].listing.status, ListingStatus::Started);
+    }
+}
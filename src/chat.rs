@@ -0,0 +1,328 @@
+//! Text chat and quick emotes between the two players. Chat has a reliable
+//! channel of its own, so it never sits in front of lobby messages or gets
+//! eaten by whoever reads those.
+
+use std::f32::consts::{FRAC_PI_2, TAU};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::{HashMap, Instant};
+use bevy_egui::{egui, EguiContexts};
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::serde_json;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::{BarCamera, Nostr, Player},
+    network::LobbyRoom,
+    profiles::{PlayerKeys, Profiles},
+    socket_builder::CHAT_CHANNEL,
+};
+
+/// The quick chat, picked from the wheel. Sent by index so both sides show the
+/// same thing.
+pub const EMOTES: &[&str] = &["👋", "gg", "😂", "😮", "😡", "👍", "Nice shot!", "Oops"];
+const MAX_MESSAGE_LEN: usize = 200;
+const MAX_LINES: usize = 50;
+/// While the chat is closed, messages stay on screen this long
+const OVERLAY_TIME: Duration = Duration::from_secs(8);
+const BUBBLE_TIME: Duration = Duration::from_secs(3);
+const WHEEL_RADIUS: f32 = 90.0;
+/// Big enough to hit with a thumb
+const WHEEL_BUTTON: f32 = 48.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChatMessage {
+    Text(String),
+    Emote(usize),
+}
+
+impl ChatMessage {
+    fn text(&self) -> Option<String> {
+        match self {
+            ChatMessage::Text(text) => {
+                let text = text.trim();
+                (!text.is_empty()).then(|| text.chars().take(MAX_MESSAGE_LEN).collect())
+            }
+            ChatMessage::Emote(emote) => EMOTES.get(*emote).map(|emote| emote.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatLine {
+    pub from: PeerId,
+    pub text: String,
+    pub at: Instant,
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct Chat {
+    pub lines: Vec<ChatLine>,
+    draft: String,
+    /// The in-game chat box is open and takes the keyboard
+    pub open: bool,
+    focus: bool,
+    wheel: bool,
+    /// Last emote of each player, shown over their head for a moment
+    bubbles: HashMap<PeerId, (usize, Instant)>,
+}
+
+impl Chat {
+    fn push(&mut self, from: PeerId, message: &ChatMessage) {
+        let Some(text) = message.text() else {
+            return;
+        };
+        if let ChatMessage::Emote(emote) = message {
+            self.bubbles.insert(from, (*emote, Instant::now()));
+        }
+        self.lines.push(ChatLine {
+            from,
+            text,
+            at: Instant::now(),
+        });
+        if self.lines.len() > MAX_LINES {
+            self.lines.remove(0);
+        }
+    }
+}
+
+pub fn send_chat(
+    socket: &mut MatchboxSocket<MultipleChannels>,
+    chat: &mut Chat,
+    local: PeerId,
+    peer: PeerId,
+    message: ChatMessage,
+) {
+    let packet = serde_json::to_vec(&message).expect("serializing chat message");
+    socket
+        .channel(CHAT_CHANNEL)
+        .send(packet.into_boxed_slice(), peer);
+    chat.push(local, &message);
+}
+
+/// Only the player in the room with us gets heard
+pub fn receive_chat(
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut chat: ResMut<Chat>,
+    room: Res<LobbyRoom>,
+) {
+    for (peer, packet) in socket.channel(CHAT_CHANNEL).receive() {
+        if room.accepted != Some(peer) {
+            continue;
+        }
+        match serde_json::from_slice::<ChatMessage>(&packet) {
+            Ok(message) => chat.push(peer, &message),
+            Err(e) => warn!("dropping malformed chat message from {peer:?}: {e}"),
+        }
+    }
+}
+
+fn chat_lines(
+    ui: &mut egui::Ui,
+    chat: &Chat,
+    profiles: &mut Profiles,
+    local: PeerId,
+    max_age: Option<Duration>,
+) {
+    for line in &chat.lines {
+        if max_age.map_or(false, |max_age| line.at.elapsed() > max_age) {
+            continue;
+        }
+        let name = if line.from == local {
+            "you".to_string()
+        } else {
+            profiles.name(line.from.0)
+        };
+        ui.horizontal_wrapped(|ui| {
+            ui.strong(format!("{name}:"));
+            ui.label(&line.text);
+        });
+    }
+}
+
+/// The log, an input line and the quick chat row. Returns what to send.
+pub fn chat_box(
+    ui: &mut egui::Ui,
+    chat: &mut Chat,
+    profiles: &mut Profiles,
+    local: PeerId,
+) -> Option<ChatMessage> {
+    let mut message = None;
+    egui::ScrollArea::vertical()
+        .max_height(120.0)
+        .stick_to_bottom(true)
+        .show(ui, |ui| {
+            chat_lines(ui, chat, profiles, local, None);
+        });
+    ui.horizontal(|ui| {
+        let response = ui.add(
+            egui::TextEdit::singleline(&mut chat.draft)
+                .hint_text("Say something")
+                .char_limit(MAX_MESSAGE_LEN),
+        );
+        if chat.focus {
+            response.request_focus();
+            chat.focus = false;
+        }
+        let entered = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        if (ui.button("Send").clicked() || entered) && !chat.draft.trim().is_empty() {
+            message = Some(ChatMessage::Text(std::mem::take(&mut chat.draft)));
+        }
+    });
+    ui.horizontal_wrapped(|ui| {
+        for (i, emote) in EMOTES.iter().enumerate() {
+            if ui.small_button(*emote).clicked() {
+                message = Some(ChatMessage::Emote(i));
+            }
+        }
+    });
+    message
+}
+
+/// Emotes around the middle of the screen, returns the one tapped
+fn emote_wheel(ctx: &egui::Context) -> Option<usize> {
+    let center = ctx.screen_rect().center();
+    let mut picked = None;
+    for (i, emote) in EMOTES.iter().enumerate() {
+        let angle = i as f32 / EMOTES.len() as f32 * TAU - FRAC_PI_2;
+        let pos = center + egui::vec2(angle.cos(), angle.sin()) * WHEEL_RADIUS;
+        egui::Area::new(format!("emote_wheel_{i}"))
+            .fixed_pos(pos)
+            .pivot(egui::Align2::CENTER_CENTER)
+            .show(ctx, |ui| {
+                let button = egui::Button::new(egui::RichText::new(*emote).size(20.0))
+                    .min_size(egui::vec2(WHEEL_BUTTON, WHEEL_BUTTON));
+                if ui.add(button).clicked() {
+                    picked = Some(i);
+                }
+            });
+    }
+    picked
+}
+
+/// Enter opens the chat and sends, Escape closes it, T opens the emote wheel.
+/// The buttons do the same on touch screens.
+pub fn game_chat(
+    mut contexts: EguiContexts,
+    keys: Res<Input<KeyCode>>,
+    mut chat: ResMut<Chat>,
+    mut profiles: ResMut<Profiles>,
+    socket: Option<ResMut<MatchboxSocket<MultipleChannels>>>,
+    room: Option<Res<LobbyRoom>>,
+    nostr_query: Query<&Nostr>,
+) {
+    let local = PeerId(nostr_query.single().keys.public_key());
+    if !chat.open {
+        if keys.just_pressed(KeyCode::Return) {
+            chat.open = true;
+            chat.focus = true;
+        } else if keys.just_pressed(KeyCode::T) {
+            chat.wheel = !chat.wheel;
+        }
+    } else if keys.just_pressed(KeyCode::Escape) {
+        chat.open = false;
+    }
+
+    let ctx = contexts.ctx_mut();
+    let mut message = None;
+    egui::Window::new("Chat")
+        .title_bar(false)
+        .resizable(false)
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::Vec2::new(8.0, -8.0))
+        .show(ctx, |ui| {
+            if chat.open {
+                message = chat_box(ui, &mut chat, &mut profiles, local);
+                if message.is_some() {
+                    chat.open = false;
+                }
+            } else {
+                chat_lines(ui, &chat, &mut profiles, local, Some(OVERLAY_TIME));
+            }
+            ui.horizontal(|ui| {
+                if ui.button("💬").on_hover_text("Chat (Enter)").clicked() {
+                    chat.open = !chat.open;
+                    chat.focus = chat.open;
+                }
+                if ui.button("🙂").on_hover_text("Emotes (T)").clicked() {
+                    chat.wheel = !chat.wheel;
+                }
+            });
+        });
+
+    if chat.wheel {
+        if let Some(emote) = emote_wheel(ctx) {
+            message = Some(ChatMessage::Emote(emote));
+            chat.wheel = false;
+        }
+    }
+
+    let (Some(message), Some(mut socket), Some(peer)) =
+        (message, socket, room.and_then(|room| room.accepted))
+    else {
+        return;
+    };
+    send_chat(&mut socket, &mut chat, local, peer, message);
+}
+
+/// The last emote over each player's head
+pub fn emote_bubbles(
+    mut contexts: EguiContexts,
+    chat: Res<Chat>,
+    keys: Res<PlayerKeys>,
+    players: Query<(&Player, &GlobalTransform)>,
+    camera: Query<(&Camera, &GlobalTransform), With<BarCamera>>,
+    window: Query<&Window>,
+) {
+    let (Ok((camera, camera_transform)), Ok(window)) = (camera.get_single(), window.get_single())
+    else {
+        return;
+    };
+
+    for (player, transform) in players.iter() {
+        let Some(public_key) = keys.0.get(player.handle) else {
+            continue;
+        };
+        let Some((emote, at)) = chat.bubbles.get(&PeerId(*public_key)) else {
+            continue;
+        };
+        if at.elapsed() > BUBBLE_TIME {
+            continue;
+        }
+        // above the name tag
+        let above = transform.translation() + Vec3::new(0.0, 1.5, 0.0);
+        let Some(viewport) = camera.world_to_viewport(camera_transform, above) else {
+            continue;
+        };
+        let pos = egui::Pos2::new(viewport.x, window.height() - viewport.y);
+        egui::Area::new(format!("emote_bubble_{}", player.handle))
+            .fixed_pos(pos)
+            .pivot(egui::Align2::CENTER_BOTTOM)
+            .interactable(false)
+            .show(contexts.ctx_mut(), |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.label(egui::RichText::new(EMOTES[*emote]).size(18.0));
+                });
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_cleaned_up() {
+        assert_eq!(
+            ChatMessage::Text("  hi ".to_string()).text(),
+            Some("hi".to_string())
+        );
+        assert_eq!(ChatMessage::Text("   ".to_string()).text(), None);
+        let long = "a".repeat(MAX_MESSAGE_LEN * 2);
+        assert_eq!(
+            ChatMessage::Text(long).text().map(|text| text.len()),
+            Some(MAX_MESSAGE_LEN)
+        );
+        assert_eq!(ChatMessage::Emote(EMOTES.len()).text(), None);
+    }
+}
@@ -1,6 +1,9 @@
 use bevy::prelude::*;
 use bevy_mod_simplest_healthbar::HealthTrait;
 use nostr_sdk::Keys;
+use serde::{Deserialize, Serialize};
+
+use crate::fixed::{Fixed, FixedVec2};
 
 #[derive(Component, Reflect, Default)]
 pub struct BulletReady {
@@ -8,24 +11,26 @@ pub struct BulletReady {
 }
 
 #[derive(Component, Reflect, Default, Clone, Copy)]
-pub struct MoveDir(pub Vec2);
+pub struct MoveDir(pub FixedVec2);
 
-#[derive(Component)]
+/// Where something is in the simulation, `Transform` is only derived from this
+/// for drawing
+#[derive(Component, Reflect, Default, Clone, Copy)]
+pub struct Position(pub FixedVec2);
+
+#[derive(Component, Reflect, Default)]
 pub struct Player {
     pub handle: usize,
     pub moving: bool,
 }
 
 #[derive(Default, Reflect, Component)]
-pub struct Target {
-    pub x: f32,
-    pub y: f32,
-}
+pub struct Target(pub FixedVec2);
 
 #[derive(Component, Reflect, Default)]
 pub struct Bullet {
     pub shooter: usize,
-    pub traveled: f32,
+    pub traveled: Fixed,
     pub despawned: bool,
     pub hit: bool,
 }
@@ -48,11 +53,44 @@ impl HealthTrait for Health {
     }
 }
 
+/// What a player did this match, rolled back with the rest. Hits and damage
+/// dealt are the opponent's `damage_taken`, every hit takes one point.
+#[derive(
+    Component, Reflect, Default, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct PlayerStats {
+    pub shots: u32,
+    pub damage_taken: u32,
+    pub healed: u32,
+}
+
+/// `PlayerStats` by handle of whoever went down, their entity doesn't outlive
+/// them. Rolled back too.
+#[derive(Resource, Reflect, Default, Debug)]
+pub struct FallenStats(pub [PlayerStats; 2]);
+
+/// Everyone's stats by handle, the living and the fallen
+pub fn stats_by_handle<'a>(
+    players: impl Iterator<Item = (&'a Player, &'a PlayerStats)>,
+    fallen: &FallenStats,
+) -> [PlayerStats; 2] {
+    let mut stats = fallen.0;
+    for (player, player_stats) in players {
+        if let Some(slot) = stats.get_mut(player.handle) {
+            *slot = *player_stats;
+        }
+    }
+    stats
+}
+
 #[derive(Component)]
 pub struct BarCamera;
 
 #[derive(Component)]
 pub struct Nostr {
     pub keys: Keys,
+    /// Everything lobby related goes out to all of these
+    pub relays: Vec<String>,
+    /// The one the WebRTC signalling runs through, see `pick_signalling_relay`
     pub relay: String,
 }
@@ -0,0 +1,244 @@
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::input::TARGET_FRAC_BITS;
+
+const FRAC_BITS: u32 = 16;
+
+/// 16.16 fixed point number.
+///
+/// All gameplay math runs on these so a simulation step gives bit identical
+/// results on every browser, CPU and on native, floats are only used for drawing.
+#[derive(
+    Reflect,
+    Default,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+pub struct Fixed(pub i32);
+
+impl Fixed {
+    pub const ZERO: Self = Self(0);
+    pub const ONE: Self = Self(1 << FRAC_BITS);
+
+    pub const fn from_int(value: i32) -> Self {
+        Self(value << FRAC_BITS)
+    }
+
+    /// For constants only, never feed the result of float math into this
+    pub const fn from_f32(value: f32) -> Self {
+        Self((value * (1 << FRAC_BITS) as f32) as i32)
+    }
+
+    /// Converts a quantized input coordinate, see `CustomInput::target_x`
+    pub const fn from_input(value: i16) -> Self {
+        Self((value as i32) << (FRAC_BITS - TARGET_FRAC_BITS))
+    }
+
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / (1 << FRAC_BITS) as f32
+    }
+
+    pub fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Self::ZERO;
+        }
+        Self(isqrt((self.0 as u64) << FRAC_BITS) as i32)
+    }
+}
+
+impl Add for Fixed {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, rhs: Self) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, rhs: Self) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self(((self.0 as i64 * rhs.0 as i64) >> FRAC_BITS) as i32)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self {
+        Self((((self.0 as i64) << FRAC_BITS) / rhs.0 as i64) as i32)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self(-self.0)
+    }
+}
+
+#[derive(Reflect, Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FixedVec2 {
+    pub x: Fixed,
+    pub y: Fixed,
+}
+
+impl FixedVec2 {
+    pub const ZERO: Self = Self::new(Fixed::ZERO, Fixed::ZERO);
+    pub const X: Self = Self::new(Fixed::ONE, Fixed::ZERO);
+    pub const NEG_X: Self = Self::new(Fixed(-Fixed::ONE.0), Fixed::ZERO);
+
+    pub const fn new(x: Fixed, y: Fixed) -> Self {
+        Self { x, y }
+    }
+
+    pub const fn from_f32(x: f32, y: f32) -> Self {
+        Self::new(Fixed::from_f32(x), Fixed::from_f32(y))
+    }
+
+    /// Squared length in raw 32.32 units, wide enough to never overflow
+    pub fn length_squared_raw(self) -> u64 {
+        let x = self.x.0 as i64;
+        let y = self.y.0 as i64;
+        (x * x) as u64 + (y * y) as u64
+    }
+
+    pub fn length(self) -> Fixed {
+        Fixed(isqrt(self.length_squared_raw()) as i32)
+    }
+
+    pub fn distance(self, other: Self) -> Fixed {
+        (self - other).length()
+    }
+
+    pub fn normalize_or_zero(self) -> Self {
+        let length = self.length();
+        if length == Fixed::ZERO {
+            return Self::ZERO;
+        }
+        Self::new(self.x / length, self.y / length)
+    }
+
+    pub fn to_vec2(self) -> Vec2 {
+        Vec2::new(self.x.to_f32(), self.y.to_f32())
+    }
+}
+
+impl Add for FixedVec2 {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.x + rhs.x, self.y + rhs.y)
+    }
+}
+
+impl AddAssign for FixedVec2 {
+    fn add_assign(&mut self, rhs: Self) {
+        self.x += rhs.x;
+        self.y += rhs.y;
+    }
+}
+
+impl Sub for FixedVec2 {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.x - rhs.x, self.y - rhs.y)
+    }
+}
+
+impl Mul<Fixed> for FixedVec2 {
+    type Output = Self;
+    fn mul(self, rhs: Fixed) -> Self {
+        Self::new(self.x * rhs, self.y * rhs)
+    }
+}
+
+impl Neg for FixedVec2 {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self::new(-self.x, -self.y)
+    }
+}
+
+fn isqrt(n: u64) -> u64 {
+    let mut rest = n;
+    let mut root = 0;
+    let mut bit = 1 << 62;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rest >= root + bit {
+            rest -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_sqrt() {
+        for n in [0, 1, 2, 3, 4, 15, 16, 17, 1 << 40, u64::MAX] {
+            let root = isqrt(n) as u128;
+            assert!(root * root <= n as u128);
+            assert!((root + 1) * (root + 1) > n as u128);
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = Fixed::from_f32(1.5);
+        let b = Fixed::from_int(2);
+        assert_eq!(a * b, Fixed::from_int(3));
+        assert_eq!(Fixed::from_int(3) / b, a);
+        assert_eq!(Fixed::from_int(9).sqrt(), Fixed::from_int(3));
+    }
+
+    #[test]
+    fn input_coordinates() {
+        assert_eq!(Fixed::from_input(1024), Fixed::ONE);
+        assert_eq!(Fixed::from_input(-512), Fixed::from_f32(-0.5));
+    }
+
+    #[test]
+    fn normalize() {
+        let v = FixedVec2::new(Fixed::from_int(3), Fixed::from_int(4));
+        assert_eq!(v.length(), Fixed::from_int(5));
+        let n = v.normalize_or_zero();
+        assert_eq!(n, FixedVec2::from_f32(0.6, 0.8));
+        assert_eq!(FixedVec2::ZERO.normalize_or_zero(), FixedVec2::ZERO);
+    }
+}
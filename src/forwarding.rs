@@ -0,0 +1,38 @@
+//! How GGRS packets travel through a game server. Each one is prefixed with a
+//! player's key: on the way in the player it's for, on the way out the player
+//! it came from. The server never looks past that.
+//!
+//! Shared by the game and the `game_server` binary.
+
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+
+const KEY_LEN: usize = 32;
+
+pub fn wrap(player: XOnlyPublicKey, payload: &[u8]) -> Box<[u8]> {
+    let mut packet = Vec::with_capacity(KEY_LEN + payload.len());
+    packet.extend_from_slice(&player.serialize());
+    packet.extend_from_slice(payload);
+    packet.into_boxed_slice()
+}
+
+pub fn unwrap(packet: &[u8]) -> Option<(XOnlyPublicKey, &[u8])> {
+    if packet.len() < KEY_LEN {
+        return None;
+    }
+    let (player, payload) = packet.split_at(KEY_LEN);
+    Some((XOnlyPublicKey::from_slice(player).ok()?, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::Keys;
+
+    #[test]
+    fn packets_round_trip() {
+        let player = Keys::generate().public_key();
+        let packet = wrap(player, b"inputs");
+        assert_eq!(unwrap(&packet), Some((player, &b"inputs"[..])));
+        assert_eq!(unwrap(&packet[..KEY_LEN - 1]), None);
+    }
+}
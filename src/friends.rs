@@ -0,0 +1,592 @@
+//! Friends, whether they're around, and challenging them directly.
+//!
+//! Every client keeps a short lived presence event up to date, so friends can
+//! see who is online or looking for a game. A challenge opens a private game
+//! and DMs its invite token to the friend, NIP-04 encrypted like join requests.
+
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::{HashMap, Instant};
+use bevy_egui::{egui, EguiContexts};
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::nips::nip04;
+use nostr_sdk::prelude::{FromBech32, ToBech32};
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{
+    serde_json, Client, ClientMessage, Event, EventBuilder, Filter, Kind, RelayPoolNotification,
+    Tag, TagKind, Timestamp,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::Nostr,
+    identity::ExtensionIdentity,
+    invite::JoinForm,
+    lobby::{HostedGame, Listing},
+    network::{send_lobby_message, LobbyMessage, LobbyRole, LobbyRoom},
+    profiles::ProfileUi,
+    quick_match::QuickMatchSearch,
+    relays, send_new_peer, storage,
+    tasks::spawn,
+    GameState,
+};
+
+const FRIENDS_KEY: &str = "fightgame.friends";
+/// Parameterized replaceable, one per player
+const PRESENCE_KIND: u64 = 30_423;
+const PRESENCE_IDENTIFIER: &str = "presence";
+/// Friends that stopped refreshing count as offline after this
+const PRESENCE_TTL: Duration = Duration::from_secs(3 * 60);
+const PRESENCE_REFRESH: Duration = Duration::from_secs(60);
+/// Older challenges aren't shown, the challenger stops waiting after this
+const CHALLENGE_TTL: Duration = Duration::from_secs(2 * 60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Online,
+    /// Hosting, in a lobby or in the quick match queue
+    Looking,
+    InGame,
+}
+
+impl Status {
+    fn label(self) -> (egui::Color32, &'static str) {
+        match self {
+            Status::Online => (egui::Color32::LIGHT_GREEN, "online"),
+            Status::Looking => (egui::Color32::YELLOW, "looking for a game"),
+            Status::InGame => (egui::Color32::LIGHT_BLUE, "in a match"),
+        }
+    }
+}
+
+/// DM'd to a friend, see `JoinRequest` for the other side of the token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    /// Relay the challenger signals through
+    pub relay: String,
+    pub token: String,
+}
+
+/// DM'd back to the challenger when we turn it down
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Declined {
+    /// `Challenge::token` of the challenge
+    pub declined: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct IncomingChallenge {
+    pub from: XOnlyPublicKey,
+    pub challenge: Challenge,
+    /// unix seconds
+    pub sent_at: u64,
+}
+
+#[derive(Resource, Default)]
+pub struct Friends {
+    /// Kept in storage
+    pub list: Vec<XOnlyPublicKey>,
+    /// Status and when it runs out, unix seconds
+    presence: Arc<Mutex<HashMap<XOnlyPublicKey, (Status, u64)>>>,
+    challenges: Arc<Mutex<Vec<IncomingChallenge>>>,
+    /// Who turned down which of our challenges, by token
+    declined: Arc<Mutex<Vec<(XOnlyPublicKey, String)>>>,
+    /// Follows from our contact list, added once they arrive
+    imported: Arc<Mutex<Option<Vec<XOnlyPublicKey>>>>,
+    /// The friends the running subscription covers
+    subscribed: Option<Vec<XOnlyPublicKey>>,
+    client: Arc<Mutex<Option<Client>>>,
+    published: Option<(Status, Instant)>,
+    new_friend: String,
+    error: Option<String>,
+}
+
+impl Friends {
+    pub fn load() -> Self {
+        let list = storage_list().unwrap_or_default();
+        Self { list, ..default() }
+    }
+
+    fn save(&self) {
+        let npubs: Vec<String> = self
+            .list
+            .iter()
+            .map(|friend| friend.to_bech32().unwrap())
+            .collect();
+        let record = serde_json::to_string(&npubs).expect("serializing friends");
+        storage::save(FRIENDS_KEY, &record);
+    }
+
+    fn add(&mut self, friend: XOnlyPublicKey) {
+        if !self.list.contains(&friend) {
+            self.list.push(friend);
+        }
+    }
+
+    pub fn status(&self, friend: &XOnlyPublicKey) -> Option<Status> {
+        let now = Timestamp::now().as_u64();
+        self.presence
+            .lock()
+            .unwrap()
+            .get(friend)
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(status, _)| *status)
+    }
+}
+
+fn storage_list() -> Option<Vec<XOnlyPublicKey>> {
+    let npubs: Vec<String> = serde_json::from_str(&storage::load(FRIENDS_KEY)?).ok()?;
+    Some(
+        npubs
+            .iter()
+            .filter_map(|npub| XOnlyPublicKey::from_bech32(npub).ok())
+            .collect(),
+    )
+}
+
+fn tag_value(event: &Event, name: &str) -> Option<String> {
+    event
+        .tags
+        .iter()
+        .map(|tag| tag.as_vec())
+        .find(|tag| tag.len() >= 2 && tag[0] == name)
+        .map(|tag| tag[1].clone())
+}
+
+/// Keeps our presence event current while the app runs
+pub fn publish_presence(
+    mut friends: ResMut<Friends>,
+    nostr_query: Query<&Nostr>,
+    state: Res<State<GameState>>,
+    search: Option<Res<QuickMatchSearch>>,
+    hosted: Option<Res<HostedGame>>,
+) {
+    let Ok(nostr) = nostr_query.get_single() else {
+        return;
+    };
+    let status = match state.0 {
+        GameState::AssetLoading => return,
+        GameState::InGame => Status::InGame,
+        GameState::Matchmaking => Status::Looking,
+        GameState::Menu if search.is_some() || hosted.is_some() => Status::Looking,
+        GameState::Menu => Status::Online,
+    };
+    let due = friends.published.map_or(true, |(published, at)| {
+        published != status || at.elapsed() >= PRESENCE_REFRESH
+    });
+    if !due {
+        return;
+    }
+    friends.published = Some((status, Instant::now()));
+
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+    let content = serde_json::to_string(&status).expect("serializing presence");
+    let expiration = Timestamp::now().as_u64() + PRESENCE_TTL.as_secs();
+    let tags = [
+        Tag::Generic(
+            TagKind::Custom("d".to_string()),
+            vec![PRESENCE_IDENTIFIER.to_string()],
+        ),
+        Tag::Generic(
+            TagKind::Custom("expiration".to_string()),
+            vec![expiration.to_string()],
+        ),
+    ];
+    spawn(async move {
+        let event = EventBuilder::new(Kind::from(PRESENCE_KIND), content, &tags)
+            .to_event(&keys)
+            .unwrap();
+        let client = relays::connect(&keys, &relays).await;
+        if let Err(e) = client.send_msg(ClientMessage::new_event(event)).await {
+            warn!("failed to publish presence: {e}");
+        }
+        client.disconnect().await.ok();
+    });
+}
+
+/// Follows friends' presence and challenges sent to us. Starts over when the
+/// list changes.
+pub fn listen_friends(mut friends: ResMut<Friends>, nostr_query: Query<&Nostr>) {
+    let Ok(nostr) = nostr_query.get_single() else {
+        return;
+    };
+    if friends.subscribed.as_ref() == Some(&friends.list) {
+        return;
+    }
+    friends.subscribed = Some(friends.list.clone());
+    if let Some(client) = friends.client.lock().unwrap().take() {
+        spawn(async move {
+            client.shutdown().await.ok();
+        });
+    }
+
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+    let list = friends.list.clone();
+    let presence = friends.presence.clone();
+    let challenges = friends.challenges.clone();
+    let declined = friends.declined.clone();
+    let client_handle = friends.client.clone();
+    spawn(async move {
+        let Ok(secret_key) = keys.secret_key() else {
+            return;
+        };
+        let client = relays::connect(&keys, &relays).await;
+        let mut filters = vec![Filter::new()
+            .kind(Kind::EncryptedDirectMessage)
+            .pubkey(keys.public_key())
+            .since(Timestamp::now() - CHALLENGE_TTL)];
+        // no authors would match everyone
+        if !list.is_empty() {
+            filters.push(
+                Filter::new()
+                    .kind(Kind::from(PRESENCE_KIND))
+                    .authors(list.clone())
+                    .since(Timestamp::now() - PRESENCE_TTL),
+            );
+        }
+        client.subscribe(filters).await;
+        *client_handle.lock().unwrap() = Some(client.clone());
+
+        client
+            .handle_notifications(move |notification| {
+                let presence = presence.clone();
+                let challenges = challenges.clone();
+                let declined = declined.clone();
+                let list = list.clone();
+                async move {
+                    let RelayPoolNotification::Event(_, event) = notification else {
+                        return Ok(());
+                    };
+                    if !list.contains(&event.pubkey) {
+                        return Ok(());
+                    }
+                    if event.kind == Kind::from(PRESENCE_KIND) {
+                        let expires_at = tag_value(&event, "expiration")
+                            .and_then(|expiration| expiration.parse().ok())
+                            .unwrap_or(event.created_at.as_u64() + PRESENCE_TTL.as_secs());
+                        if let Ok(status) = serde_json::from_str::<Status>(&event.content) {
+                            presence
+                                .lock()
+                                .unwrap()
+                                .insert(event.pubkey, (status, expires_at));
+                        }
+                    } else if let Ok(content) =
+                        nip04::decrypt(&secret_key, &event.pubkey, &event.content)
+                    {
+                        // signalling and join requests come through here too
+                        if let Ok(challenge) = serde_json::from_str::<Challenge>(&content) {
+                            let mut challenges = challenges.lock().unwrap();
+                            challenges.retain(|known| known.from != event.pubkey);
+                            challenges.push(IncomingChallenge {
+                                from: event.pubkey,
+                                challenge,
+                                sent_at: event.created_at.as_u64(),
+                            });
+                        } else if let Ok(answer) = serde_json::from_str::<Declined>(&content) {
+                            declined
+                                .lock()
+                                .unwrap()
+                                .push((event.pubkey, answer.declined));
+                        }
+                    }
+                    Ok(())
+                }
+            })
+            .await
+            .ok();
+    });
+}
+
+/// Adds whoever we follow (NIP-02), on our key and the extension's
+fn import_follows(friends: &Friends, nostr: &Nostr, extension: &ExtensionIdentity) {
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+    let mut authors = vec![keys.public_key()];
+    authors.extend(extension.public_key());
+    let imported = friends.imported.clone();
+    spawn(async move {
+        let client = relays::connect(&keys, &relays).await;
+        let filter = Filter::new().kind(Kind::ContactList).authors(authors);
+        match client
+            .get_events_of(vec![filter], Some(FETCH_TIMEOUT))
+            .await
+        {
+            Ok(events) => {
+                // the newest list of each author is the current one
+                let mut newest: HashMap<XOnlyPublicKey, &Event> = HashMap::new();
+                for event in &events {
+                    let known = newest.entry(event.pubkey).or_insert(event);
+                    if event.created_at > known.created_at {
+                        *known = event;
+                    }
+                }
+                let follows = newest
+                    .values()
+                    .flat_map(|event| event.tags.iter())
+                    .map(|tag| tag.as_vec())
+                    .filter(|tag| tag.len() >= 2 && tag[0] == "p")
+                    .filter_map(|tag| XOnlyPublicKey::from_str(&tag[1]).ok())
+                    .collect();
+                *imported.lock().unwrap() = Some(follows);
+            }
+            Err(e) => warn!("failed to fetch the contact list: {e}"),
+        }
+        client.disconnect().await.ok();
+    });
+}
+
+/// NIP-04 DMs `content` to `friend`
+fn send_dm(nostr: &Nostr, friend: XOnlyPublicKey, content: String, what: &'static str) {
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+    spawn(async move {
+        let client = relays::connect(&keys, &relays).await;
+        if let Err(e) = client.send_direct_msg(friend, content).await {
+            warn!("failed to send the {what}: {e}");
+        }
+        client.disconnect().await.ok();
+    });
+}
+
+/// Sends `friend` the invite to a private game we're about to host
+fn challenge(nostr: &Nostr, friend: XOnlyPublicKey, challenge: &Challenge) {
+    let content = serde_json::to_string(challenge).expect("serializing challenge");
+    send_dm(nostr, friend, content, "challenge");
+}
+
+/// Lets the challenger know, so they stop waiting for us
+fn decline(nostr: &Nostr, incoming: &IncomingChallenge) {
+    let answer = Declined {
+        declined: incoming.challenge.token.clone(),
+    };
+    let content = serde_json::to_string(&answer).expect("serializing decline");
+    send_dm(nostr, incoming.from, content, "decline");
+}
+
+/// The friend we challenged, let in as soon as they connect
+#[derive(Resource, Debug)]
+pub struct ChallengeSent {
+    pub friend: XOnlyPublicKey,
+    token: String,
+    sent_at: Instant,
+}
+
+/// Lets the friend in once they connect, gives up when they decline or don't
+/// show up in time
+pub fn accept_challenger(
+    challenged: Res<ChallengeSent>,
+    friends: Res<Friends>,
+    mut room: ResMut<LobbyRoom>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut join_form: ResMut<JoinForm>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let friend = PeerId(challenged.friend);
+    if room.accepted.is_none() && socket.connected_peers().any(|peer| peer == friend) {
+        room.accepted = Some(friend);
+        send_lobby_message(&mut socket, friend, &LobbyMessage::Accepted);
+    }
+    if room.accepted.is_some() {
+        return;
+    }
+
+    let declined = friends
+        .declined
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(from, token)| *from == challenged.friend && *token == challenged.token);
+    if declined {
+        join_form.error = Some("Your challenge was declined".to_string());
+    } else if challenged.sent_at.elapsed() >= CHALLENGE_TTL {
+        join_form.error = Some("Nobody answered your challenge".to_string());
+    } else {
+        return;
+    }
+    // entering the menu forgets the challenge
+    next_state.set(GameState::Menu);
+}
+
+pub fn forget_challenge(mut commands: Commands) {
+    commands.remove_resource::<ChallengeSent>();
+}
+
+enum FriendAction {
+    Challenge(XOnlyPublicKey),
+    Accept(IncomingChallenge),
+    Decline(IncomingChallenge),
+    Remove(XOnlyPublicKey),
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn friends_panel(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut friends: ResMut<Friends>,
+    mut nostr_query: Query<&mut Nostr>,
+    extension: Res<ExtensionIdentity>,
+    mut role: ResMut<LobbyRole>,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut profile_ui: ProfileUi,
+) {
+    let mut nostr = nostr_query.single_mut();
+    let imported = friends.imported.lock().unwrap().take();
+    if let Some(imported) = imported {
+        let me = nostr.keys.public_key();
+        for friend in imported.into_iter().filter(|friend| *friend != me) {
+            friends.add(friend);
+        }
+        friends.save();
+    }
+    let now = Timestamp::now().as_u64();
+    friends
+        .challenges
+        .lock()
+        .unwrap()
+        .retain(|challenge| challenge.sent_at + CHALLENGE_TTL.as_secs() > now);
+    let challenges = friends.challenges.lock().unwrap().clone();
+
+    let mut action = None;
+    egui::Window::new("Friends")
+        .resizable(false)
+        .default_open(false)
+        .anchor(egui::Align2::LEFT_CENTER, egui::Vec2::new(8.0, 0.0))
+        .show(contexts.ctx_mut(), |ui| {
+            for incoming in &challenges {
+                ui.horizontal(|ui| {
+                    profile_ui.label(ui, incoming.from);
+                    ui.label("challenges you!");
+                    if ui.button("Accept").clicked() {
+                        action = Some(FriendAction::Accept(incoming.clone()));
+                    }
+                    if ui.button("Decline").clicked() {
+                        action = Some(FriendAction::Decline(incoming.clone()));
+                    }
+                });
+            }
+            if !challenges.is_empty() {
+                ui.separator();
+            }
+
+            if friends.list.is_empty() {
+                ui.label("No friends added yet.");
+            }
+            let mut list = friends.list.clone();
+            // whoever is around first
+            list.sort_by_key(|friend| friends.status(friend).is_none());
+            for friend in list {
+                let status = friends.status(&friend);
+                ui.horizontal(|ui| {
+                    let (color, text) =
+                        status.map_or((egui::Color32::GRAY, "offline"), Status::label);
+                    ui.colored_label(color, "●").on_hover_text(text);
+                    profile_ui.label(ui, friend);
+                    ui.weak(text);
+                    let available = matches!(status, Some(Status::Online | Status::Looking));
+                    if ui
+                        .add_enabled(available, egui::Button::new("Challenge"))
+                        .clicked()
+                    {
+                        action = Some(FriendAction::Challenge(friend));
+                    }
+                    if ui.small_button("✖").clicked() {
+                        action = Some(FriendAction::Remove(friend));
+                    }
+                });
+            }
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut friends.new_friend).hint_text("npub1..."));
+                if ui.button("Add").clicked() {
+                    match XOnlyPublicKey::from_bech32(friends.new_friend.trim()) {
+                        Ok(friend) => {
+                            friends.add(friend);
+                            friends.save();
+                            friends.new_friend.clear();
+                            friends.error = None;
+                        }
+                        Err(_) => friends.error = Some("Not a valid npub".to_string()),
+                    }
+                }
+            });
+            if ui.small_button("Import follows").clicked() {
+                import_follows(&friends, &nostr, &extension);
+            }
+            if let Some(error) = &friends.error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+        });
+
+    match action {
+        Some(FriendAction::Challenge(friend)) => {
+            let name = format!("Challenge for {}", profile_ui.profiles.name(friend));
+            let hosted = HostedGame::new(&nostr, Listing::new(name), true, None);
+            let invite = Challenge {
+                relay: nostr.relay.clone(),
+                token: hosted.token.clone(),
+            };
+            challenge(&nostr, friend, &invite);
+            commands.insert_resource(ChallengeSent {
+                friend,
+                token: hosted.token.clone(),
+                sent_at: Instant::now(),
+            });
+            commands.insert_resource(hosted);
+            *role = LobbyRole::Host;
+            next_state.set(GameState::Matchmaking);
+        }
+        Some(FriendAction::Accept(incoming)) => {
+            nostr.relay = incoming.challenge.relay.clone();
+            send_new_peer(&nostr, incoming.from, Some(incoming.challenge.token));
+            friends
+                .challenges
+                .lock()
+                .unwrap()
+                .retain(|challenge| challenge.from != incoming.from);
+            *role = LobbyRole::Joiner;
+            next_state.set(GameState::Matchmaking);
+        }
+        Some(FriendAction::Decline(incoming)) => {
+            decline(&nostr, &incoming);
+            friends
+                .challenges
+                .lock()
+                .unwrap()
+                .retain(|challenge| challenge.from != incoming.from);
+        }
+        Some(FriendAction::Remove(friend)) => {
+            friends.list.retain(|known| *known != friend);
+            friends.save();
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenges_and_declines_tell_apart() {
+        let challenge = serde_json::to_string(&Challenge {
+            relay: "wss://relay.example.com".to_string(),
+            token: "abc".to_string(),
+        })
+        .unwrap();
+        let declined = serde_json::to_string(&Declined {
+            declined: "abc".to_string(),
+        })
+        .unwrap();
+
+        assert!(serde_json::from_str::<Declined>(&challenge).is_err());
+        assert!(serde_json::from_str::<Challenge>(&declined).is_err());
+    }
+}
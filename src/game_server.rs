@@ -0,0 +1,174 @@
+//! Playing through a game server instead of straight to each other. The host
+//! picks one in the lobby, and once the match starts both players connect to
+//! it like to any other peer and send their GGRS packets there, see
+//! `forwarding`. Helps when the direct connection is slow or drops packets.
+//!
+//! The server has to listen on the host's signalling relay. The lobby, chat,
+//! result signing and rematches still go straight between the players, so it
+//! doesn't help players who can't connect to each other at all, a symmetric NAT
+//! on both ends still keeps them apart.
+//!
+//! Whoever loses their own link to the server mid match forfeits, see
+//! `handle_ggrs_events`.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use bevy_egui::{egui, EguiContexts};
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::prelude::{FromBech32, ToBech32};
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+
+use crate::{
+    components::Nostr,
+    invite::JoinForm,
+    network::{start_session_on, GgrsChannel, LobbyRole, LobbyRoom, NetSettings},
+    send_new_peer, storage, GameState,
+};
+
+const SERVER_KEY: &str = "fightgame.server";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The server the host plays through, remembered across sessions
+#[derive(Resource, Debug, Default)]
+pub struct GameServer {
+    pub enabled: bool,
+    npub: String,
+}
+
+impl GameServer {
+    pub fn load() -> Self {
+        let npub = storage::load(SERVER_KEY).unwrap_or_default();
+        Self {
+            enabled: !npub.is_empty(),
+            npub,
+        }
+    }
+
+    pub fn key(&self) -> Option<XOnlyPublicKey> {
+        XOnlyPublicKey::from_bech32(self.npub.trim()).ok()
+    }
+}
+
+/// The match is waiting on the game server to connect before the session
+/// starts
+#[derive(Resource, Debug)]
+pub struct ServerConnection {
+    server: PeerId,
+    opponent: PeerId,
+    settings: NetSettings,
+    requested: bool,
+    deadline: Instant,
+}
+
+impl ServerConnection {
+    pub fn new(server: PeerId, opponent: PeerId, settings: NetSettings) -> Self {
+        Self {
+            server,
+            opponent,
+            settings,
+            requested: false,
+            deadline: Instant::now() + CONNECT_TIMEOUT,
+        }
+    }
+}
+
+pub fn game_server_panel(
+    mut contexts: EguiContexts,
+    mut server: ResMut<GameServer>,
+    mut room: ResMut<LobbyRoom>,
+    role: Res<LobbyRole>,
+    nostr_query: Query<&Nostr>,
+) {
+    if *role != LobbyRole::Host {
+        return;
+    }
+    let mut changed = false;
+    egui::Window::new("Game server")
+        .resizable(false)
+        .default_open(false)
+        .anchor(egui::Align2::RIGHT_TOP, egui::Vec2::new(-8.0, 8.0))
+        .show(contexts.ctx_mut(), |ui| {
+            changed |= ui
+                .checkbox(&mut server.enabled, "Play through a game server")
+                .changed();
+            ui.add_enabled_ui(server.enabled, |ui| {
+                changed |= ui
+                    .add(egui::TextEdit::singleline(&mut server.npub).hint_text("npub..."))
+                    .changed();
+                if server.key().is_none() && !server.npub.trim().is_empty() {
+                    ui.colored_label(egui::Color32::RED, "Not a valid npub");
+                }
+                ui.weak(format!(
+                    "It has to listen on {}",
+                    nostr_query.single().relay
+                ));
+            });
+        });
+
+    room.server = server.key().filter(|_| server.enabled);
+    if changed {
+        let npub = room.server.and_then(|key| key.to_bech32().ok());
+        match npub {
+            Some(npub) => storage::save(SERVER_KEY, &npub),
+            None if !server.enabled => storage::remove(SERVER_KEY),
+            None => {}
+        }
+    }
+}
+
+/// Asks the server for a connection and starts the session once it's there
+pub fn connect_to_server(
+    mut commands: Commands,
+    mut connection: ResMut<ServerConnection>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    channel: Res<GgrsChannel>,
+    nostr_query: Query<&Nostr>,
+    mut join_form: ResMut<JoinForm>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let nostr = nostr_query.single();
+    if !connection.requested {
+        connection.requested = true;
+        info!("connecting to game server {:?}", connection.server);
+        send_new_peer(nostr, connection.server.0, None);
+    }
+
+    // nothing else looks at the socket until there's a session
+    socket.update_peers();
+    if socket
+        .connected_peers()
+        .any(|peer| peer == connection.server)
+    {
+        let local = PeerId(nostr.keys.public_key());
+        start_session_on(
+            &mut commands,
+            channel.clone(),
+            local,
+            connection.opponent,
+            connection.settings,
+        );
+        commands.remove_resource::<ServerConnection>();
+        return;
+    }
+
+    if Instant::now() >= connection.deadline {
+        warn!("game server {:?} didn't connect", connection.server);
+        join_form.error = Some("Couldn't reach the game server".to_string());
+        next_state.set(GameState::Menu);
+    }
+}
+
+pub fn waiting_for_server_banner(mut contexts: EguiContexts) {
+    egui::Window::new("Connecting")
+        .title_bar(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_TOP, egui::Vec2::new(0.0, 16.0))
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Connecting to the game server...");
+            });
+        });
+}
@@ -0,0 +1,493 @@
+//! Matches we played, kept in storage and, if the player wants, on their
+//! relays as NIP-78 app data so the history follows the key to other devices.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{
+    serde_json, ClientMessage, Event, EventBuilder, Filter, Kind, Tag, TagKind, Timestamp,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::{stats_by_handle, FallenStats, Nostr, Player, PlayerStats},
+    net_stats::FrameCount,
+    network::{Forfeit, FPS},
+    profiles::{PlayerKeys, ProfileUi},
+    ratings::Leaderboard,
+    relays,
+    results::MatchOver,
+    storage,
+    tasks::spawn,
+    LocalPlayerHandle,
+};
+
+const HISTORY_KEY: &str = "fightgame.history";
+const PUBLISH_KEY: &str = "fightgame.history.publish";
+/// NIP-78 arbitrary app data
+const APP_DATA_KIND: u64 = 30_078;
+/// Older matches are dropped, keeps the event small
+const HISTORY_LIMIT: usize = 100;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const SHOWN_MATCHES: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Win,
+    Loss,
+    Draw,
+    /// The opponent left and didn't come back
+    Forfeit,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MatchRecord {
+    /// unix seconds
+    pub played_at: u64,
+    pub opponent: XOnlyPublicKey,
+    pub outcome: Outcome,
+    pub duration_secs: u32,
+    pub shots: u32,
+    pub hits: u32,
+    pub damage_taken: u32,
+    pub healed: u32,
+}
+
+impl MatchRecord {
+    /// Every hit deals one point
+    pub fn damage_dealt(&self) -> u32 {
+        self.hits
+    }
+
+    pub fn accuracy(&self) -> Option<f32> {
+        (self.shots > 0).then(|| self.hits as f32 / self.shots as f32)
+    }
+
+    fn won(&self) -> bool {
+        matches!(self.outcome, Outcome::Win | Outcome::Forfeit)
+    }
+
+    /// Our side of a match, `stats` by handle
+    fn new(
+        opponent: XOnlyPublicKey,
+        outcome: Outcome,
+        frames: u32,
+        local: usize,
+        stats: [PlayerStats; 2],
+    ) -> Self {
+        let (mine, theirs) = (stats[local], stats[1 - local]);
+        Self {
+            played_at: Timestamp::now().as_u64(),
+            opponent,
+            outcome,
+            duration_secs: frames / FPS as u32,
+            shots: mine.shots,
+            hits: theirs.damage_taken,
+            damage_taken: mine.damage_taken,
+            healed: mine.healed,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Totals {
+    pub matches: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+    pub shots: u32,
+    pub hits: u32,
+    pub damage_taken: u32,
+    pub healed: u32,
+    pub duration_secs: u32,
+}
+
+impl Totals {
+    pub fn of(records: &[MatchRecord]) -> Self {
+        let mut totals = Self::default();
+        for record in records {
+            totals.matches += 1;
+            match record.outcome {
+                _ if record.won() => totals.wins += 1,
+                Outcome::Loss => totals.losses += 1,
+                _ => totals.draws += 1,
+            }
+            totals.shots += record.shots;
+            totals.hits += record.hits;
+            totals.damage_taken += record.damage_taken;
+            totals.healed += record.healed;
+            totals.duration_secs += record.duration_secs;
+        }
+        totals
+    }
+
+    pub fn win_rate(&self) -> Option<f32> {
+        (self.matches > 0).then(|| self.wins as f32 / self.matches as f32)
+    }
+
+    pub fn accuracy(&self) -> Option<f32> {
+        (self.shots > 0).then(|| self.hits as f32 / self.shots as f32)
+    }
+}
+
+/// Newest first, without the matches both lists have
+fn merge(mut records: Vec<MatchRecord>, other: Vec<MatchRecord>) -> Vec<MatchRecord> {
+    for record in other {
+        let known = records
+            .iter()
+            .any(|known| known.played_at == record.played_at && known.opponent == record.opponent);
+        if !known {
+            records.push(record);
+        }
+    }
+    records.sort_by_key(|record| std::cmp::Reverse(record.played_at));
+    records.truncate(HISTORY_LIMIT);
+    records
+}
+
+#[derive(Resource, Debug)]
+pub struct MatchHistory {
+    /// Newest first
+    pub records: Vec<MatchRecord>,
+    /// Also keep the history on our relays
+    pub publish: bool,
+    /// What our relays had, merged in once it arrives
+    remote: Arc<Mutex<Option<Vec<MatchRecord>>>>,
+    fetched: bool,
+}
+
+impl MatchHistory {
+    pub fn load() -> Self {
+        let records = storage::load(HISTORY_KEY)
+            .and_then(|records| serde_json::from_str(&records).ok())
+            .unwrap_or_default();
+        Self {
+            records,
+            publish: storage::load(PUBLISH_KEY).map_or(false, |publish| publish == "true"),
+            remote: Default::default(),
+            fetched: false,
+        }
+    }
+
+    fn save(&self) {
+        let records = serde_json::to_string(&self.records).expect("serializing history");
+        storage::save(HISTORY_KEY, &records);
+        storage::save(PUBLISH_KEY, &self.publish.to_string());
+    }
+
+    fn add(&mut self, record: MatchRecord, nostr: &Nostr) {
+        self.records = merge(vec![record], std::mem::take(&mut self.records));
+        self.save();
+        if self.publish {
+            self.publish(nostr);
+        }
+    }
+
+    fn publish(&self, nostr: &Nostr) {
+        let keys = nostr.keys.clone();
+        let relays = nostr.relays.clone();
+        let content = serde_json::to_string(&self.records).expect("serializing history");
+        let tags = [Tag::Generic(
+            TagKind::Custom("d".to_string()),
+            vec![HISTORY_KEY.to_string()],
+        )];
+        spawn(async move {
+            let event = EventBuilder::new(Kind::from(APP_DATA_KIND), content, &tags)
+                .to_event(&keys)
+                .unwrap();
+            let client = relays::connect(&keys, &relays).await;
+            if let Err(e) = client.send_msg(ClientMessage::new_event(event)).await {
+                warn!("failed to publish the match history: {e}");
+            }
+            client.disconnect().await.ok();
+        });
+    }
+
+    fn fetch(&mut self, nostr: &Nostr) {
+        self.fetched = true;
+        let keys = nostr.keys.clone();
+        let relays = nostr.relays.clone();
+        let remote = self.remote.clone();
+        spawn(async move {
+            let client = relays::connect(&keys, &relays).await;
+            let filter = Filter::new()
+                .kind(Kind::from(APP_DATA_KIND))
+                .authors(vec![keys.public_key()]);
+            match client
+                .get_events_of(vec![filter], Some(FETCH_TIMEOUT))
+                .await
+            {
+                Ok(events) => {
+                    let records = events
+                        .iter()
+                        .filter(|event| is_history(event))
+                        .max_by_key(|event| event.created_at)
+                        .and_then(|event| serde_json::from_str(&event.content).ok());
+                    *remote.lock().unwrap() = Some(records.unwrap_or_default());
+                }
+                Err(e) => warn!("failed to fetch the match history: {e}"),
+            }
+            client.disconnect().await.ok();
+        });
+    }
+}
+
+fn is_history(event: &Event) -> bool {
+    event.tags.iter().any(|tag| {
+        let tag = tag.as_vec();
+        tag.len() >= 2 && tag[0] == "d" && tag[1] == HISTORY_KEY
+    })
+}
+
+/// Writes the match down once it's decided, either way
+#[allow(clippy::too_many_arguments)]
+pub fn record_match(
+    mut history: ResMut<MatchHistory>,
+    over: Option<Res<MatchOver>>,
+    forfeit: Option<Res<Forfeit>>,
+    players: Query<(&Player, &PlayerStats)>,
+    fallen: Res<FallenStats>,
+    local: Res<LocalPlayerHandle>,
+    keys: Res<PlayerKeys>,
+    frame_count: Res<FrameCount>,
+    nostr_query: Query<&Nostr>,
+) {
+    let nostr = nostr_query.single();
+    let me = nostr.keys.public_key();
+    let Some(opponent) = keys.0.iter().copied().find(|key| *key != me) else {
+        return;
+    };
+    let (outcome, frames, stats) = match (&over, &forfeit) {
+        (Some(over), _) => {
+            let outcome = match over.result.winner {
+                Some(winner) if winner == me => Outcome::Win,
+                Some(_) => Outcome::Loss,
+                None => Outcome::Draw,
+            };
+            // whoever went down is despawned by now, `MatchOver` kept theirs
+            (outcome, over.frames, over.stats)
+        }
+        (None, Some(forfeit)) if forfeit.ours => (
+            Outcome::Loss,
+            frame_count.0,
+            stats_by_handle(players.iter(), &fallen),
+        ),
+        (None, Some(_)) => (
+            Outcome::Forfeit,
+            frame_count.0,
+            stats_by_handle(players.iter(), &fallen),
+        ),
+        (None, None) => return,
+    };
+
+    let record = MatchRecord::new(opponent, outcome, frames, local.0, stats);
+    info!("recording match {record:?}");
+    history.add(record, nostr);
+}
+
+fn percent(value: Option<f32>) -> String {
+    value.map_or("-".to_string(), |value| format!("{:.0}%", value * 100.0))
+}
+
+fn minutes(secs: u32) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+pub fn stats_panel(
+    mut contexts: EguiContexts,
+    mut history: ResMut<MatchHistory>,
+    leaderboard: Res<Leaderboard>,
+    nostr_query: Query<&Nostr>,
+    mut profile_ui: ProfileUi,
+) {
+    let nostr = nostr_query.single();
+    if !history.fetched {
+        history.fetch(nostr);
+    }
+    let remote = history.remote.lock().unwrap().take();
+    if let Some(remote) = remote {
+        let records = std::mem::take(&mut history.records);
+        history.records = merge(records, remote);
+        history.save();
+    }
+
+    egui::Window::new("Stats")
+        .resizable(false)
+        .default_open(false)
+        .anchor(egui::Align2::RIGHT_TOP, egui::Vec2::new(-8.0, 8.0))
+        .show(contexts.ctx_mut(), |ui| {
+            let totals = Totals::of(&history.records);
+            ui.label(format!(
+                "Rating: {}",
+                leaderboard.rating_of(&nostr.keys.public_key())
+            ));
+            ui.label(format!(
+                "{} matches, {}W {}L {}D, win rate {}",
+                totals.matches,
+                totals.wins,
+                totals.losses,
+                totals.draws,
+                percent(totals.win_rate())
+            ));
+            ui.label(format!(
+                "Accuracy {} ({} of {} shots)",
+                percent(totals.accuracy()),
+                totals.hits,
+                totals.shots
+            ));
+            ui.label(format!(
+                "Damage dealt {}, taken {}, healed {}",
+                totals.hits, totals.damage_taken, totals.healed
+            ));
+            if totals.matches > 0 {
+                ui.label(format!(
+                    "Average match {}",
+                    minutes(totals.duration_secs / totals.matches)
+                ));
+            }
+
+            let mut publish = history.publish;
+            if ui
+                .checkbox(&mut publish, "Keep my history on my relays")
+                .changed()
+            {
+                history.publish = publish;
+                history.save();
+                if publish {
+                    history.publish(nostr);
+                }
+            }
+
+            ui.separator();
+            if history.records.is_empty() {
+                ui.label("No matches played yet.");
+            }
+            let now = Timestamp::now().as_u64();
+            egui::ScrollArea::vertical()
+                .max_height(240.0)
+                .show(ui, |ui| {
+                    egui::Grid::new("history").striped(true).show(ui, |ui| {
+                        for record in history.records.iter().take(SHOWN_MATCHES) {
+                            let (color, outcome) = match record.outcome {
+                                Outcome::Win => (egui::Color32::LIGHT_GREEN, "Win"),
+                                Outcome::Forfeit => (egui::Color32::LIGHT_GREEN, "Win (forfeit)"),
+                                Outcome::Loss => (egui::Color32::RED, "Loss"),
+                                Outcome::Draw => (egui::Color32::GRAY, "Draw"),
+                            };
+                            ui.colored_label(color, outcome);
+                            profile_ui.label(ui, record.opponent);
+                            ui.label(minutes(record.duration_secs));
+                            ui.label(format!(
+                                "{} acc, {} dealt, {} taken",
+                                percent(record.accuracy()),
+                                record.damage_dealt(),
+                                record.damage_taken
+                            ));
+                            ui.weak(format!(
+                                "{}h ago",
+                                now.saturating_sub(record.played_at) / 3600
+                            ));
+                            ui.end_row();
+                        }
+                    });
+                });
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::Keys;
+
+    fn record(played_at: u64, opponent: XOnlyPublicKey, outcome: Outcome) -> MatchRecord {
+        MatchRecord {
+            played_at,
+            opponent,
+            outcome,
+            duration_secs: 60,
+            shots: 10,
+            hits: 4,
+            damage_taken: 3,
+            healed: 1,
+        }
+    }
+
+    #[test]
+    fn totals_add_up() {
+        let opponent = Keys::generate().public_key();
+        let records = [
+            record(1, opponent, Outcome::Win),
+            record(2, opponent, Outcome::Loss),
+            record(3, opponent, Outcome::Forfeit),
+        ];
+        let totals = Totals::of(&records);
+        assert_eq!((totals.wins, totals.losses, totals.draws), (2, 1, 0));
+        assert_eq!(totals.accuracy(), Some(0.4));
+        assert_eq!(totals.duration_secs, 180);
+    }
+
+    #[test]
+    fn merge_skips_known_matches() {
+        let opponent = Keys::generate().public_key();
+        let local = vec![record(2, opponent, Outcome::Win)];
+        let remote = vec![
+            record(1, opponent, Outcome::Loss),
+            record(2, opponent, Outcome::Win),
+        ];
+        let merged = merge(local, remote);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].played_at, 2);
+    }
+
+    #[test]
+    fn knocked_out_players_keep_their_stats() {
+        use crate::components::Health;
+        use crate::spells::update_health;
+
+        let mut world = World::new();
+        world.init_resource::<FallenStats>();
+        let winner = PlayerStats {
+            shots: 8,
+            damage_taken: 2,
+            healed: 1,
+        };
+        let loser = PlayerStats {
+            shots: 5,
+            damage_taken: 3,
+            healed: 0,
+        };
+        for (handle, stats, health) in [(0, winner, 2), (1, loser, 0)] {
+            world.spawn((
+                Player {
+                    handle,
+                    moving: false,
+                },
+                Health {
+                    current: health,
+                    max: 3,
+                },
+                stats,
+            ));
+        }
+        let mut schedule = Schedule::new();
+        schedule.add_system(update_health);
+        schedule.run(&mut world);
+
+        // the loser is gone, what they did isn't
+        let mut players = world.query::<(&Player, &PlayerStats)>();
+        assert_eq!(players.iter(&world).count(), 1);
+        let stats = stats_by_handle(players.iter(&world), world.resource::<FallenStats>());
+        assert_eq!(stats, [winner, loser]);
+
+        let opponent = Keys::generate().public_key();
+        let won = MatchRecord::new(opponent, Outcome::Win, 600, 0, stats);
+        assert_eq!((won.shots, won.hits, won.damage_taken), (8, 3, 2));
+        assert_eq!(won.accuracy(), Some(3.0 / 8.0));
+        let lost = MatchRecord::new(opponent, Outcome::Loss, 600, 1, stats);
+        assert_eq!((lost.shots, lost.hits, lost.damage_taken), (5, 2, 3));
+    }
+}
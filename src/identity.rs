@@ -0,0 +1,143 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use nostr_sdk::prelude::{FromBech32, ToBech32};
+use nostr_sdk::secp256k1::{SecretKey, XOnlyPublicKey};
+use nostr_sdk::Keys;
+
+use crate::{components::Nostr, nip07, storage, tasks::spawn};
+
+const SECRET_KEY: &str = "fightgame.nsec";
+
+/// The key we played with last time, or a fresh one that is kept from now on
+pub fn load_or_create_keys() -> Keys {
+    if let Some(keys) = storage::load(SECRET_KEY).and_then(|nsec| parse_secret_key(&nsec)) {
+        return keys;
+    }
+    let keys = Keys::generate();
+    save_keys(&keys);
+    keys
+}
+
+pub fn save_keys(keys: &Keys) {
+    if let Ok(secret_key) = keys.secret_key() {
+        storage::save(SECRET_KEY, &secret_key.to_bech32().unwrap());
+    }
+}
+
+/// Accepts an nsec or a hex secret key
+pub fn parse_secret_key(secret_key: &str) -> Option<Keys> {
+    let secret_key = secret_key.trim();
+    SecretKey::from_bech32(secret_key)
+        .ok()
+        .or_else(|| SecretKey::from_str(secret_key).ok())
+        .map(Keys::new)
+}
+
+/// Public key of a NIP-07 extension the player signed in with. Public events
+/// like game listings are signed with it, signalling stays on the local key.
+#[derive(Resource, Default, Clone)]
+pub struct ExtensionIdentity(pub Arc<Mutex<Option<XOnlyPublicKey>>>);
+
+impl ExtensionIdentity {
+    pub fn public_key(&self) -> Option<XOnlyPublicKey> {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct IdentityForm {
+    pub import: String,
+    pub show_nsec: bool,
+    pub error: Arc<Mutex<Option<String>>>,
+}
+
+pub fn identity_panel(
+    mut contexts: EguiContexts,
+    mut nostr_query: Query<&mut Nostr>,
+    mut form: ResMut<IdentityForm>,
+    extension: Res<ExtensionIdentity>,
+) {
+    let mut nostr = nostr_query.single_mut();
+    let npub = nostr.keys.public_key().to_bech32().unwrap();
+
+    egui::Window::new("Identity")
+        .resizable(false)
+        .default_open(false)
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::Vec2::new(8.0, -8.0))
+        .show(contexts.ctx_mut(), |ui| {
+            if let Some(public_key) = extension.public_key() {
+                ui.label("Signed in with browser extension:");
+                ui.monospace(public_key.to_bech32().unwrap());
+                // the extension can't sign for the WebRTC signalling, and a
+                // prompt for every event would get in the way
+                ui.weak("Only your game listings are signed with it,");
+                ui.weak("everything else uses the key below.");
+                ui.separator();
+            }
+
+            ui.label("Your key:");
+            ui.horizontal(|ui| {
+                ui.monospace(&npub);
+                if ui.small_button("Copy").clicked() {
+                    ui.output_mut(|o| o.copied_text = npub.clone());
+                }
+            });
+
+            ui.checkbox(&mut form.show_nsec, "Show secret key");
+            if form.show_nsec {
+                if let Ok(secret_key) = nostr.keys.secret_key() {
+                    let nsec = secret_key.to_bech32().unwrap();
+                    ui.horizontal(|ui| {
+                        ui.monospace(&nsec);
+                        if ui.small_button("Copy").clicked() {
+                            ui.output_mut(|o| o.copied_text = nsec.clone());
+                        }
+                    });
+                    ui.label("Anyone with this key can play as you, keep it safe.");
+                }
+            }
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut form.import)
+                        .hint_text("nsec1...")
+                        .password(true),
+                );
+                if ui.button("Import").clicked() {
+                    match parse_secret_key(&form.import) {
+                        Some(keys) => {
+                            save_keys(&keys);
+                            nostr.keys = keys;
+                            form.import.clear();
+                            *form.error.lock().unwrap() = None;
+                        }
+                        None => {
+                            *form.error.lock().unwrap() = Some("Not a valid nsec".to_string());
+                        }
+                    }
+                }
+            });
+
+            if nip07::available()
+                && extension.public_key().is_none()
+                && ui.button("Sign in with browser extension").clicked()
+            {
+                let extension = extension.0.clone();
+                let error = form.error.clone();
+                spawn(async move {
+                    match nip07::get_public_key().await {
+                        Ok(public_key) => *extension.lock().unwrap() = Some(public_key),
+                        Err(e) => *error.lock().unwrap() = Some(e),
+                    }
+                });
+            }
+
+            if let Some(error) = form.error.lock().unwrap().as_ref() {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+        });
+}
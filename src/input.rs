@@ -2,28 +2,113 @@ use std::time::Duration;
 
 use bevy::prelude::*;
 use bevy::utils::Instant;
+use bevy_egui::EguiContexts;
 use bevy_ggrs::{ggrs, PlayerInputs, Rollback};
 use bytemuck::{Pod, Zeroable};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    components::{MoveDir, Player, Target},
+    chat::Chat,
+    components::{MoveDir, Player, Position, Target},
+    fixed::{Fixed, FixedVec2},
     GgrsConfig,
 };
 
 const INPUT_MOVE: u8 = 1 << 0;
 const INPUT_FIRE: u8 = 1 << 1;
+const INPUT_BUTTONS: u8 = INPUT_MOVE | INPUT_FIRE;
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+const INPUT_UP: u8 = 1 << 0;
+const INPUT_DOWN: u8 = 1 << 1;
+const INPUT_LEFT: u8 = 1 << 2;
+const INPUT_RIGHT: u8 = 1 << 3;
+const INPUT_DIRECTIONS: u8 = INPUT_UP | INPUT_DOWN | INPUT_LEFT | INPUT_RIGHT;
+
+/// Targets are sent as fixed point with 1/1024 of a world unit precision
+pub const TARGET_FRAC_BITS: u32 = 10;
+pub const TARGET_SCALE: f32 = (1 << TARGET_FRAC_BITS) as f32;
+/// Half the side of the square a target can be in, anything outside is clamped
+pub const ARENA_BOUND: f32 = 16.0;
+const ARENA_BOUND_FIXED: i16 = (ARENA_BOUND * TARGET_SCALE) as i16;
+
+const PLAYER_SPEED: Fixed = Fixed::from_f32(0.05);
+
+/// How the local player steers their ostrich. Only the resulting input bits go
+/// over the wire, so both players can pick a different scheme in the same match.
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlScheme {
+    /// MOBA style, click or tap where you want to go
+    #[default]
+    ClickToMove,
+    /// WASD / arrow keys
+    Direct,
+}
+
+/// What gets sent to the other peer every frame, 6 bytes with no padding.
+///
+/// The target is quantized to integers so both peers see bit identical values
+/// no matter how their float math rounds.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable, Serialize, Deserialize)]
 pub struct CustomInput {
-    pub inp: u8,
-    pub target_x: f32,
-    pub target_y: f32,
+    /// move / fire / ability bits
+    pub buttons: u8,
+    /// held direction bits for direct movement
+    pub dir: u8,
+    pub target_x: i16,
+    pub target_y: i16,
+}
+
+impl CustomInput {
+    pub fn set_target(&mut self, target: Vec2) {
+        self.target_x = quantize(target.x);
+        self.target_y = quantize(target.y);
+    }
+
+    pub fn fixed_target(&self) -> FixedVec2 {
+        FixedVec2::new(
+            Fixed::from_input(self.target_x),
+            Fixed::from_input(self.target_y),
+        )
+    }
+
+    pub fn target(&self) -> Vec2 {
+        Vec2::new(
+            self.target_x as f32 / TARGET_SCALE,
+            self.target_y as f32 / TARGET_SCALE,
+        )
+    }
+
+    /// Checks an input that came in from a peer, anything we couldn't have
+    /// produced ourselves is dropped as if nothing was pressed.
+    pub fn is_valid(&self) -> bool {
+        self.buttons & !INPUT_BUTTONS == 0
+            && self.dir & !INPUT_DIRECTIONS == 0
+            && (-ARENA_BOUND_FIXED..=ARENA_BOUND_FIXED).contains(&self.target_x)
+            && (-ARENA_BOUND_FIXED..=ARENA_BOUND_FIXED).contains(&self.target_y)
+    }
+
+    /// Runs on every resimulated frame, so only logs at debug level
+    pub fn validated(self) -> Self {
+        if self.is_valid() {
+            self
+        } else {
+            debug!("dropping invalid input: {:?}", self);
+            Self::default()
+        }
+    }
 }
 
-unsafe impl Zeroable for CustomInput {}
-unsafe impl Pod for CustomInput {}
+fn quantize(value: f32) -> i16 {
+    (value.clamp(-ARENA_BOUND, ARENA_BOUND) * TARGET_SCALE).round() as i16
+}
 
+/// The input for a player this frame, after validation
+pub fn player_input(inputs: &PlayerInputs<GgrsConfig>, handle: usize) -> CustomInput {
+    inputs[handle].0.validated()
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn input(
     _handle: In<ggrs::PlayerHandle>,
     keys: Res<Input<KeyCode>>,
@@ -31,65 +116,80 @@ pub fn input(
     camera_query: Query<(&Camera, &GlobalTransform)>,
     mut windows: Query<&mut Window>,
     touches: Res<Touches>,
+    scheme: Res<ControlScheme>,
+    chat: Res<Chat>,
+    mut contexts: EguiContexts,
 ) -> CustomInput {
-    let mut input = CustomInput {
-        inp: 0,
-        target_x: 0.0,
-        target_y: 0.0,
-    };
+    let mut input = CustomInput::default();
+    // taps and clicks on the chat, emote wheel and other windows aren't moves
+    let ctx = contexts.ctx_mut();
+    let over_ui = ctx.wants_pointer_input() || ctx.is_pointer_over_area();
     let mut last_touch_timestamp: Option<Instant> = None;
     let touch_threshold = Duration::from_secs_f32(2.0);
 
-    for touch in touches.iter() {
+    for touch in touches.iter().filter(|_| !over_ui) {
         let touch_pos = touch.position();
         let (camera, camera_transform) = camera_query.single();
 
         for window in windows.iter_mut() {
             let touch_position = get_touch_position(&window, camera, camera_transform, touch_pos);
-            input.target_x = touch_position.x;
-            input.target_y = touch_position.y;
+            input.set_target(touch_position);
         }
 
         // Check if the current touch is within the threshold since the last touch
         if let Some(last_timestamp) = last_touch_timestamp {
             if Instant::now().duration_since(last_timestamp) < touch_threshold {
                 // If within the threshold, trigger the shoot action and don't move
-                input.inp |= INPUT_FIRE;
+                input.buttons |= INPUT_FIRE;
                 last_touch_timestamp = None;
             } else {
                 // If not within the threshold, update the last touch timestamp and move
                 last_touch_timestamp = Some(Instant::now());
-                input.inp |= INPUT_MOVE;
+                input.buttons |= INPUT_MOVE;
             }
         } else {
             // If there was no previous touch, update the last touch timestamp and move
             last_touch_timestamp = Some(Instant::now());
-            input.inp |= INPUT_MOVE;
+            input.buttons |= INPUT_MOVE;
         }
     }
 
-    if mouse.pressed(MouseButton::Left) || mouse.pressed(MouseButton::Right) {
+    if *scheme == ControlScheme::Direct {
+        // keys typed into the chat aren't meant for the game
+        if !chat.open {
+            if keys.any_pressed([KeyCode::W, KeyCode::Up]) {
+                input.dir |= INPUT_UP;
+            }
+            if keys.any_pressed([KeyCode::S, KeyCode::Down]) {
+                input.dir |= INPUT_DOWN;
+            }
+            if keys.any_pressed([KeyCode::A, KeyCode::Left]) {
+                input.dir |= INPUT_LEFT;
+            }
+            if keys.any_pressed([KeyCode::D, KeyCode::Right]) {
+                input.dir |= INPUT_RIGHT;
+            }
+        }
+    } else if !over_ui && (mouse.pressed(MouseButton::Left) || mouse.pressed(MouseButton::Right)) {
         for window in windows.iter_mut() {
             if let Some(cursor) = window.cursor_position() {
                 let (camera, camera_transform) = camera_query.single();
                 let click_position = get_click_position(&window, camera, camera_transform, cursor);
-                input.target_x = click_position.x;
-                input.target_y = click_position.y;
+                input.set_target(click_position);
             }
         }
-        input.inp |= INPUT_MOVE;
+        input.buttons |= INPUT_MOVE;
     }
 
-    if keys.pressed(KeyCode::Q) {
+    if keys.pressed(KeyCode::Q) && !chat.open {
         for window in windows.iter_mut() {
             if let Some(cursor) = window.cursor_position() {
                 let (camera, camera_transform) = camera_query.single();
                 let click_position = get_click_position(&window, camera, camera_transform, cursor);
-                input.target_x = click_position.x;
-                input.target_y = click_position.y;
+                input.set_target(click_position);
             }
         }
-        input.inp |= INPUT_FIRE;
+        input.buttons |= INPUT_FIRE;
     }
 
     input
@@ -133,43 +233,50 @@ pub fn get_touch_position(
 }
 
 pub fn move_system(
-    mut query: Query<(&mut Transform, &mut Target, &mut Player, &mut MoveDir), With<Rollback>>,
+    mut query: Query<(&mut Position, &mut Target, &mut Player, &mut MoveDir), With<Rollback>>,
     inputs: Res<PlayerInputs<GgrsConfig>>,
 ) {
-    for (mut t, mut tg, mut p, mut move_dir) in query.iter_mut() {
-        let input = inputs[p.handle].0.inp;
+    for (mut position, mut tg, mut p, mut move_dir) in query.iter_mut() {
+        let input = player_input(&inputs, p.handle);
 
-        if input & INPUT_MOVE != 0 {
-            let click_position =
-                Vec2::new(inputs[p.handle].0.target_x, inputs[p.handle].0.target_y);
+        // direct movement always wins over a pending click target
+        let held = direction(input);
+        if held != FixedVec2::ZERO {
+            p.moving = false;
+            // normalized so diagonals are no faster than click-to-move
+            let movement = held.normalize_or_zero() * PLAYER_SPEED;
+            position.0 += movement;
 
-            tg.x = click_position.x;
-            tg.y = click_position.y;
+            if movement.x > Fixed::ZERO {
+                move_dir.0 = FixedVec2::X;
+            } else if movement.x < Fixed::ZERO {
+                move_dir.0 = FixedVec2::NEG_X;
+            }
+            continue;
+        }
+
+        if input.buttons & INPUT_MOVE != 0 {
+            tg.0 = input.fixed_target();
             p.moving = true;
         }
 
         if p.moving {
-            let current_position = Vec2::new(t.translation.x, t.translation.y);
-            let direction = Vec2::new(tg.x, tg.y) - current_position;
+            let direction = tg.0 - position.0;
             let distance_to_target = direction.length();
 
-            if distance_to_target > 0.0 {
-                let player_speed = 0.05;
-                let normalized_direction = direction / distance_to_target;
-                let movement = normalized_direction * player_speed;
+            if distance_to_target > Fixed::ZERO {
+                let normalized_direction = direction.normalize_or_zero();
 
-                if movement.length() < distance_to_target {
-                    t.translation += Vec3::new(movement.x, movement.y, 0.0);
+                if PLAYER_SPEED < distance_to_target {
+                    position.0 += normalized_direction * PLAYER_SPEED;
                 } else {
-                    t.translation = Vec3::new(tg.x, tg.y, 0.0);
+                    position.0 = tg.0;
                     p.moving = false;
                 }
-                if normalized_direction.x > 0.0 {
-                    move_dir.0 = Vec2::X;
-                    t.rotation = Quat::from_rotation_y(std::f32::consts::PI);
+                if normalized_direction.x > Fixed::ZERO {
+                    move_dir.0 = FixedVec2::X;
                 } else {
-                    move_dir.0 = -Vec2::X;
-                    t.rotation = Quat::from_rotation_y(0.0);
+                    move_dir.0 = FixedVec2::NEG_X;
                 }
             } else {
                 p.moving = false;
@@ -179,5 +286,99 @@ pub fn move_system(
 }
 
 pub fn fire(input: CustomInput) -> bool {
-    input.inp & INPUT_FIRE != 0
+    input.buttons & INPUT_FIRE != 0
+}
+
+/// Direction held with WASD / arrow keys, opposite keys cancel out
+pub fn direction(input: CustomInput) -> FixedVec2 {
+    let mut direction = FixedVec2::ZERO;
+    if input.dir & INPUT_UP != 0 {
+        direction.y += Fixed::ONE;
+    }
+    if input.dir & INPUT_DOWN != 0 {
+        direction.y -= Fixed::ONE;
+    }
+    if input.dir & INPUT_LEFT != 0 {
+        direction.x -= Fixed::ONE;
+    }
+    if input.dir & INPUT_RIGHT != 0 {
+        direction.x += Fixed::ONE;
+    }
+    direction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_is_six_bytes() {
+        assert_eq!(std::mem::size_of::<CustomInput>(), 6);
+    }
+
+    #[test]
+    fn target_round_trips() {
+        for target in [
+            Vec2::ZERO,
+            Vec2::new(-5.0, 0.0),
+            Vec2::new(3.25, -4.5),
+            Vec2::new(ARENA_BOUND, -ARENA_BOUND),
+        ] {
+            let mut input = CustomInput::default();
+            input.set_target(target);
+            assert_eq!(input.target(), target);
+        }
+    }
+
+    #[test]
+    fn target_is_within_one_step() {
+        let target = Vec2::new(1.2345, -2.3456);
+        let mut input = CustomInput::default();
+        input.set_target(target);
+        let error = (input.target() - target).abs();
+        assert!(error.x <= 0.5 / TARGET_SCALE && error.y <= 0.5 / TARGET_SCALE);
+    }
+
+    #[test]
+    fn target_is_clamped_to_arena() {
+        let mut input = CustomInput::default();
+        input.set_target(Vec2::new(1000.0, -1000.0));
+        assert_eq!(input.target(), Vec2::new(ARENA_BOUND, -ARENA_BOUND));
+        assert!(input.is_valid());
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut input = CustomInput {
+            buttons: INPUT_MOVE | INPUT_FIRE,
+            dir: INPUT_UP | INPUT_LEFT,
+            ..default()
+        };
+        input.set_target(Vec2::new(-2.5, 4.0));
+
+        let bytes = bytemuck::bytes_of(&input).to_vec();
+        let decoded: CustomInput = *bytemuck::from_bytes(&bytes);
+        assert_eq!(decoded, input);
+        assert!(decoded.is_valid());
+    }
+
+    #[test]
+    fn invalid_inputs_are_dropped() {
+        let unknown_button = CustomInput {
+            buttons: 1 << 7,
+            ..default()
+        };
+        let unknown_dir = CustomInput {
+            dir: 1 << 6,
+            ..default()
+        };
+        let out_of_bounds = CustomInput {
+            target_x: i16::MAX,
+            ..default()
+        };
+        for input in [unknown_button, unknown_dir, out_of_bounds] {
+            assert!(!input.is_valid());
+            assert_eq!(input.validated(), CustomInput::default());
+        }
+    }
 }
@@ -0,0 +1,418 @@
+//! Private and password protected games.
+//!
+//! Before asking for a WebRTC connection a joiner DMs the host a `JoinRequest`
+//! with the password or the token from an invite link. Those DMs are NIP-04
+//! encrypted, so the secret never shows up anywhere public. The host only
+//! plays with peers it got a matching request from and turns everyone else
+//! away over the lobby channel. Matchbox can't drop a single peer, so they stay
+//! connected, but nothing they send is listened to.
+//!
+//! Every key only gets a few wrong guesses, later requests are ignored.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::{HashMap, Instant};
+use bevy_egui::egui;
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::nips::nip04;
+use nostr_sdk::prelude::{FromBech32, ToBech32};
+use nostr_sdk::secp256k1::{rand, XOnlyPublicKey};
+use nostr_sdk::{serde_json, Client, EventId, Filter, Kind, RelayPoolNotification, Timestamp};
+use qrcode::QrCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::Nostr,
+    lobby::HostedGame,
+    network::{send_lobby_message, LobbyMessage},
+    relays,
+    tasks::spawn,
+    Game,
+};
+
+/// How long a connected peer gets for its join request to arrive
+const JOIN_APPROVAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wrong secrets a key may send before its requests are ignored
+const MAX_JOIN_ATTEMPTS: usize = 5;
+const INVITE_PARAM: &str = "join";
+const TOKEN_PARAM: &str = "token";
+const RELAY_PARAM: &str = "relay";
+#[cfg(not(target_arch = "wasm32"))]
+const NATIVE_INVITE_BASE: &str = "fightgame://invite";
+
+pub fn new_token() -> String {
+    format!(
+        "{:016x}{:016x}",
+        rand::random::<u64>(),
+        rand::random::<u64>()
+    )
+}
+
+/// Everything needed to join a game that isn't listed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    /// Key the host's socket signals with
+    pub host: XOnlyPublicKey,
+    pub token: String,
+    pub relay: String,
+}
+
+impl Invite {
+    pub fn link(&self) -> String {
+        format!(
+            "{}?{INVITE_PARAM}={}&{TOKEN_PARAM}={}&{RELAY_PARAM}={}",
+            invite_base(),
+            self.host.to_bech32().unwrap(),
+            self.token,
+            encode(&self.relay),
+        )
+    }
+
+    /// Takes a whole link, or just its query string
+    pub fn parse(link: &str) -> Option<Self> {
+        let query = link
+            .trim()
+            .rsplit_once('?')
+            .map_or(link, |(_, query)| query);
+        let mut host = None;
+        let mut token = None;
+        let mut relay = None;
+        for pair in query.split('&') {
+            let Some((key, value)) = pair.split_once('=') else {
+                continue;
+            };
+            match key {
+                INVITE_PARAM => host = XOnlyPublicKey::from_bech32(value).ok(),
+                TOKEN_PARAM => token = Some(value.to_string()),
+                RELAY_PARAM => relay = decode(value),
+                _ => {}
+            }
+        }
+        Some(Self {
+            host: host?,
+            token: token.filter(|token| !token.is_empty())?,
+            relay: relay?,
+        })
+    }
+}
+
+/// Percent-encodes everything but unreserved characters
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn invite_base() -> String {
+    web_sys::window()
+        .and_then(|window| {
+            let location = window.location();
+            Some(format!(
+                "{}{}",
+                location.origin().ok()?,
+                location.pathname().ok()?
+            ))
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn invite_base() -> String {
+    NATIVE_INVITE_BASE.to_string()
+}
+
+/// The invite the page was opened with
+#[cfg(target_arch = "wasm32")]
+pub fn page_invite() -> Option<Invite> {
+    Invite::parse(&web_sys::window()?.location().href().ok()?)
+}
+
+/// Invites are passed as the first argument outside the browser
+#[cfg(not(target_arch = "wasm32"))]
+pub fn page_invite() -> Option<Invite> {
+    Invite::parse(&std::env::args().nth(1)?)
+}
+
+/// DM'd to the host ahead of the connection request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JoinRequest {
+    /// The password, or the token of an invite
+    pub join_secret: String,
+}
+
+/// Menu state for joining games that need a secret
+#[derive(Resource, Default, Debug)]
+pub struct JoinForm {
+    /// Listed game we're asking the password for
+    pub password_for: Option<Game>,
+    pub password: String,
+    pub invite: String,
+    /// Why the last attempt didn't work out
+    pub error: Option<String>,
+}
+
+pub fn read_page_invite(mut form: ResMut<JoinForm>) {
+    if let Some(invite) = page_invite() {
+        form.invite = invite.link();
+    }
+}
+
+/// Wrong join requests by key. By event id, since every relay delivers its
+/// own copy of the same DM.
+#[derive(Default)]
+struct Attempts(HashMap<XOnlyPublicKey, HashSet<EventId>>);
+
+impl Attempts {
+    /// Whether `from` may join with this request
+    fn check(&mut self, from: XOnlyPublicKey, request: EventId, correct: bool) -> bool {
+        let failed = self.0.entry(from).or_default();
+        if failed.len() >= MAX_JOIN_ATTEMPTS {
+            return false;
+        }
+        if !correct {
+            failed.insert(request);
+        }
+        correct
+    }
+}
+
+/// Host side, decides who may play
+#[derive(Resource)]
+pub struct JoinGate {
+    /// Anyone may join a public game without a password
+    open: bool,
+    approved: Arc<Mutex<HashSet<XOnlyPublicKey>>>,
+    first_seen: HashMap<PeerId, Instant>,
+    rejected: HashSet<PeerId>,
+    client: Arc<Mutex<Option<Client>>>,
+}
+
+impl JoinGate {
+    pub fn admits(&self, peer: &PeerId) -> bool {
+        self.open || self.approved.lock().unwrap().contains(&peer.0)
+    }
+}
+
+/// Starts listening for join requests when we host
+pub fn open_join_gate(
+    mut commands: Commands,
+    hosted: Option<Res<HostedGame>>,
+    nostr_query: Query<&Nostr>,
+) {
+    let Some(hosted) = hosted else {
+        return;
+    };
+    let nostr = nostr_query.single();
+    let gate = JoinGate {
+        open: !hosted.private && hosted.password.is_none(),
+        approved: Default::default(),
+        first_seen: Default::default(),
+        rejected: Default::default(),
+        client: Default::default(),
+    };
+
+    if !gate.open {
+        let keys = nostr.keys.clone();
+        let mut relays = nostr.relays.clone();
+        if !relays.contains(&nostr.relay) {
+            relays.push(nostr.relay.clone());
+        }
+        let secrets: Vec<String> = [Some(hosted.token.clone()), hosted.password.clone()]
+            .into_iter()
+            .flatten()
+            .collect();
+        let approved = gate.approved.clone();
+        let attempts = Arc::new(Mutex::new(Attempts::default()));
+        let client_handle = gate.client.clone();
+        spawn(async move {
+            let Ok(secret_key) = keys.secret_key() else {
+                return;
+            };
+            let client = relays::connect(&keys, &relays).await;
+            let filter = Filter::new()
+                .kind(Kind::EncryptedDirectMessage)
+                .pubkey(keys.public_key())
+                .since(Timestamp::now() - Duration::from_secs(60));
+            client.subscribe(vec![filter]).await;
+            *client_handle.lock().unwrap() = Some(client.clone());
+
+            client
+                .handle_notifications(move |notification| {
+                    let approved = approved.clone();
+                    let attempts = attempts.clone();
+                    let secrets = secrets.clone();
+                    async move {
+                        let RelayPoolNotification::Event(_, event) = notification else {
+                            return Ok(());
+                        };
+                        let Ok(content) =
+                            nip04::decrypt(&secret_key, &event.pubkey, &event.content)
+                        else {
+                            return Ok(());
+                        };
+                        // the connection requests come through here too
+                        let Ok(request) = serde_json::from_str::<JoinRequest>(&content) else {
+                            return Ok(());
+                        };
+                        let correct = secrets.contains(&request.join_secret);
+                        if attempts
+                            .lock()
+                            .unwrap()
+                            .check(event.pubkey, event.id, correct)
+                        {
+                            info!("{} may join", event.pubkey);
+                            approved.lock().unwrap().insert(event.pubkey);
+                        } else {
+                            info!("{} sent the wrong secret or too many", event.pubkey);
+                        }
+                        Ok(())
+                    }
+                })
+                .await
+                .ok();
+        });
+    }
+    commands.insert_resource(gate);
+}
+
+pub fn close_join_gate(mut commands: Commands, gate: Option<Res<JoinGate>>) {
+    let Some(gate) = gate else {
+        return;
+    };
+    if let Some(client) = gate.client.lock().unwrap().take() {
+        spawn(async move {
+            client.shutdown().await.ok();
+        });
+    }
+    commands.remove_resource::<JoinGate>();
+}
+
+/// Turns away peers whose join request didn't show up in time
+pub fn gate_peers(
+    mut gate: ResMut<JoinGate>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+) {
+    let peers: Vec<PeerId> = socket.connected_peers().collect();
+    for peer in peers {
+        if gate.admits(&peer) || gate.rejected.contains(&peer) {
+            continue;
+        }
+        let first_seen = *gate.first_seen.entry(peer).or_insert_with(Instant::now);
+        if first_seen.elapsed() >= JOIN_APPROVAL_TIMEOUT {
+            warn!("turning away {peer:?}, no valid join request");
+            send_lobby_message(
+                &mut socket,
+                peer,
+                &LobbyMessage::Rejected("Wrong password or invite".to_string()),
+            );
+            gate.rejected.insert(peer);
+        }
+    }
+}
+
+/// Link, copy button and QR code of the game we host
+pub fn invite_panel(ui: &mut egui::Ui, invite: &Invite) {
+    let link = invite.link();
+    ui.label("Invite link:");
+    ui.horizontal(|ui| {
+        ui.monospace(&link);
+        if ui.small_button("Copy").clicked() {
+            ui.output_mut(|o| o.copied_text = link.clone());
+        }
+    });
+    if let Ok(code) = QrCode::new(link.as_bytes()) {
+        qr_code(ui, &code);
+    }
+}
+
+fn qr_code(ui: &mut egui::Ui, code: &QrCode) {
+    const MODULE: f32 = 3.0;
+    // the quiet zone scanners need around the code
+    const QUIET: usize = 4;
+    let width = code.width();
+    let side = (width + 2 * QUIET) as f32 * MODULE;
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(side, side), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, egui::Color32::WHITE);
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color != qrcode::Color::Dark {
+            continue;
+        }
+        let (x, y) = (i % width + QUIET, i / width + QUIET);
+        let min = rect.min + egui::vec2(x as f32 * MODULE, y as f32 * MODULE);
+        painter.rect_filled(
+            egui::Rect::from_min_size(min, egui::vec2(MODULE, MODULE)),
+            0.0,
+            egui::Color32::BLACK,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::Keys;
+
+    #[test]
+    fn invite_round_trip() {
+        let invite = Invite {
+            host: Keys::generate().public_key(),
+            token: new_token(),
+            relay: "wss://relay.example.com/path?x=1".to_string(),
+        };
+        assert_eq!(Invite::parse(&invite.link()), Some(invite.clone()));
+        let query = invite.link().split_once('?').unwrap().1.to_string();
+        assert_eq!(Invite::parse(&query), Some(invite));
+    }
+
+    #[test]
+    fn rejects_incomplete_invites() {
+        assert_eq!(
+            Invite::parse("https://example.com/?join=npub1nope&token=abc"),
+            None
+        );
+        assert_eq!(Invite::parse(""), None);
+    }
+
+    #[test]
+    fn guessing_runs_out() {
+        let guesser = Keys::generate().public_key();
+        let mut attempts = Attempts::default();
+        for i in 0..MAX_JOIN_ATTEMPTS {
+            let id = EventId::from_hex(&format!("{i:02x}").repeat(32)).unwrap();
+            assert!(!attempts.check(guesser, id, false));
+            // the same DM from another relay doesn't count twice
+            assert!(!attempts.check(guesser, id, false));
+        }
+        let id = EventId::from_hex(&"ff".repeat(32)).unwrap();
+        assert!(!attempts.check(guesser, id, true));
+        // others still get in
+        assert!(attempts.check(Keys::generate().public_key(), id, true));
+    }
+}
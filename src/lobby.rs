@@ -0,0 +1,274 @@
+//! Game listings. A listing is a parameterized replaceable event of its own
+//! kind, so it stays out of timelines and publishing it again under the same
+//! `d` tag updates it. Every listing expires (NIP-40) in case the host vanishes
+//! without closing it.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use nostr_sdk::prelude::ToBech32;
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{
+    serde_json, ClientMessage, Event, EventBuilder, Filter, Kind, Tag, TagKind, Timestamp,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::Nostr,
+    identity::ExtensionIdentity,
+    invite::{new_token, Invite},
+    nip07, relays,
+    tasks::spawn,
+    Game, GameState,
+};
+
+/// In the parameterized replaceable range
+pub const LISTING_KIND: u64 = 30_420;
+/// Listings older than this are gone, the host refreshes well before
+pub const LISTING_TTL: Duration = Duration::from_secs(10 * 60);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(4 * 60);
+/// Kept on listings so relay searches by hashtag still find them
+const HASHTAG: &str = "matchbox-nostr-v1";
+/// Points from a listing to the key the host signals with, see `Game::peer`
+pub const SIGNAL_TAG: &str = "signal";
+/// Names the relay the host signals through, see `Game::relay`
+pub const RELAY_TAG: &str = "relay";
+const EXPIRATION_TAG: &str = "expiration";
+const IDENTIFIER_TAG: &str = "d";
+
+pub const GAME_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const MODES: &[&str] = &["duel"];
+pub const MAPS: &[&str] = &["arena"];
+pub const REGIONS: &[&str] = &["any", "eu", "na", "sa", "asia", "oceania"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ListingStatus {
+    #[default]
+    Open,
+    /// The match is running, nobody else can join
+    Started,
+    /// The host gave up waiting
+    Closed,
+}
+
+/// What a listing event carries as its content
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    pub name: String,
+    pub mode: String,
+    pub players: u8,
+    pub map: String,
+    pub region: String,
+    pub version: String,
+    pub password: bool,
+    #[serde(default)]
+    pub status: ListingStatus,
+}
+
+impl Listing {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            mode: MODES[0].to_string(),
+            players: 2,
+            map: MAPS[0].to_string(),
+            region: REGIONS[0].to_string(),
+            version: GAME_VERSION.to_string(),
+            password: false,
+            status: ListingStatus::Open,
+        }
+    }
+}
+
+/// The game we're hosting. Unless it's private the listing is republished to
+/// refresh the expiry and closed once the match starts.
+#[derive(Resource, Debug, Clone)]
+pub struct HostedGame {
+    /// `d` tag, stays the same across updates
+    pub id: String,
+    pub listing: Listing,
+    /// Not listed, only joinable through the invite
+    pub private: bool,
+    pub password: Option<String>,
+    /// Goes into the invite link, works in place of the password
+    pub token: String,
+    last_published: Instant,
+}
+
+impl HostedGame {
+    pub fn new(
+        nostr: &Nostr,
+        mut listing: Listing,
+        private: bool,
+        password: Option<String>,
+    ) -> Self {
+        listing.password = password.is_some();
+        Self {
+            id: format!("{}-{}", nostr.keys.public_key(), Timestamp::now().as_u64()),
+            listing,
+            private,
+            password,
+            token: new_token(),
+            last_published: Instant::now(),
+        }
+    }
+
+    pub fn invite(&self, nostr: &Nostr) -> Invite {
+        Invite {
+            host: nostr.keys.public_key(),
+            token: self.token.clone(),
+            relay: nostr.relay.clone(),
+        }
+    }
+}
+
+/// The unsigned listing event for `hosted`
+pub fn listing_builder(nostr: &Nostr, hosted: &HostedGame) -> EventBuilder {
+    let content = serde_json::to_string(&hosted.listing).expect("serializing listing");
+    let expiration = Timestamp::now().as_u64() + LISTING_TTL.as_secs();
+    let tags = [
+        Tag::Generic(
+            TagKind::Custom(IDENTIFIER_TAG.to_string()),
+            vec![hosted.id.clone()],
+        ),
+        Tag::Hashtag(HASHTAG.to_string()),
+        Tag::Generic(
+            TagKind::Custom(SIGNAL_TAG.to_string()),
+            vec![nostr.keys.public_key().to_string()],
+        ),
+        Tag::Generic(
+            TagKind::Custom(RELAY_TAG.to_string()),
+            vec![nostr.relay.clone()],
+        ),
+        Tag::Generic(
+            TagKind::Custom(EXPIRATION_TAG.to_string()),
+            vec![expiration.to_string()],
+        ),
+    ];
+    EventBuilder::new(Kind::from(LISTING_KIND), content, &tags)
+}
+
+/// Signs the listing, with the extension if one is signed in, and sends it to
+/// every relay since joiners may be on any of them
+pub fn publish_listing(nostr: &Nostr, extension: &ExtensionIdentity, hosted: &HostedGame) {
+    if hosted.private {
+        return;
+    }
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+    let extension_key = extension.public_key();
+    let builder = listing_builder(nostr, hosted);
+
+    info!("publishing listing {:?} to {:?}", hosted.listing, relays);
+    spawn(async move {
+        let event = match extension_key {
+            Some(public_key) => {
+                match nip07::sign_event(builder.to_unsigned_event(public_key)).await {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("extension didn't sign the listing: {e}");
+                        return;
+                    }
+                }
+            }
+            None => builder.to_event(&keys).unwrap(),
+        };
+
+        let client = relays::connect(&keys, &relays).await;
+        if let Err(e) = client.send_msg(ClientMessage::new_event(event)).await {
+            warn!("failed to publish the listing: {e}");
+        }
+        client.disconnect().await.ok();
+    });
+}
+
+/// Republishes before the expiry runs out while we wait for someone
+pub fn refresh_listing(
+    mut hosted: ResMut<HostedGame>,
+    nostr_query: Query<&Nostr>,
+    extension: Res<ExtensionIdentity>,
+) {
+    if hosted.last_published.elapsed() < REFRESH_INTERVAL {
+        return;
+    }
+    hosted.last_published = Instant::now();
+    publish_listing(nostr_query.single(), &extension, &hosted);
+}
+
+/// Marks the listing started, or closed if we left without playing, so
+/// browsers drop it
+pub fn close_listing(
+    mut commands: Commands,
+    hosted: Option<Res<HostedGame>>,
+    nostr_query: Query<&Nostr>,
+    extension: Res<ExtensionIdentity>,
+    state: Res<State<GameState>>,
+) {
+    let Some(hosted) = hosted else {
+        return;
+    };
+    let mut hosted = hosted.clone();
+    // on exit the state already is the one we're going to
+    hosted.listing.status = if state.0 == GameState::InGame {
+        ListingStatus::Started
+    } else {
+        ListingStatus::Closed
+    };
+    publish_listing(nostr_query.single(), &extension, &hosted);
+    commands.remove_resource::<HostedGame>();
+}
+
+pub fn listing_filter() -> Filter {
+    Filter::new()
+        .kind(Kind::from(LISTING_KIND))
+        .since(Timestamp::now() - LISTING_TTL)
+}
+
+fn tag_value(event: &Event, name: &str) -> Option<String> {
+    event
+        .tags
+        .iter()
+        .map(|tag| tag.as_vec())
+        .find(|tag| tag.len() >= 2 && tag[0] == name)
+        .map(|tag| tag[1].clone())
+}
+
+/// NIP-40 expiration, unix seconds
+fn expiration(event: &Event) -> Option<u64> {
+    tag_value(event, EXPIRATION_TAG).and_then(|expiration| expiration.parse().ok())
+}
+
+/// The game a listing event describes. `relay` is where it came from, the
+/// fallback for listings without a relay tag.
+pub fn parse_listing(event: &Event, relay: &str) -> Option<Game> {
+    let expires_at = expiration(event);
+    let expired = expires_at.map_or(false, |expires_at| expires_at <= Timestamp::now().as_u64());
+    if event.kind != Kind::from(LISTING_KIND) || expired {
+        return None;
+    }
+    let listing: Listing = match serde_json::from_str(&event.content) {
+        Ok(listing) => listing,
+        Err(e) => {
+            info!("ignoring malformed listing {}: {e}", event.id);
+            return None;
+        }
+    };
+    // listings signed by an extension point at the socket's key with a tag
+    let peer = tag_value(event, SIGNAL_TAG)
+        .and_then(|peer| XOnlyPublicKey::from_str(&peer).ok())
+        .unwrap_or(event.pubkey);
+
+    Some(Game {
+        listing,
+        created_by: event.pubkey.to_bech32().unwrap(),
+        peer: peer.to_bech32().unwrap(),
+        relay: tag_value(event, RELAY_TAG).unwrap_or_else(|| relay.to_string()),
+        id: event.id,
+        d: tag_value(event, IDENTIFIER_TAG).unwrap_or_default(),
+        created_at: event.created_at.as_u64(),
+        expires_at,
+    })
+}
@@ -0,0 +1,203 @@
+//! Two headless clients against the bundled relay: the host lists a game, the
+//! joiner finds it and asks to join, then both bring up the WebRTC connection,
+//! go through the lobby room and start a GGRS session over it. Each client is
+//! a bare `App` running the game's own socket setup and `wait_for_players`,
+//! the test only clicks what a player would. Nothing leaves this machine.
+
+use std::future::Future;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_ggrs::ggrs::SessionState;
+use bevy_ggrs::Session;
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::prelude::FromBech32;
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{ClientMessage, Keys};
+use tokio::net::TcpListener;
+
+use crate::{
+    components::Nostr,
+    invite::JoinForm,
+    lobby::{listing_builder, listing_filter, parse_listing, HostedGame, Listing},
+    local_relay,
+    network::{
+        start_matchbox_socket, wait_for_players, LobbyRole, LobbyRoom, NetSettings, RoomAction,
+    },
+    relays, request_connection,
+    socket_builder::CHAT_CHANNEL,
+    GameState, GgrsConfig,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(20);
+const POLL: Duration = Duration::from_millis(10);
+
+async fn start_relay() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(local_relay::serve(listener));
+    url
+}
+
+fn client(relay: &str) -> Nostr {
+    Nostr {
+        keys: Keys::generate(),
+        relays: vec![relay.to_string()],
+        relay: relay.to_string(),
+    }
+}
+
+/// In the lobby, with the socket open. The task pools run its message loop.
+fn client_app(nostr: &Nostr, role: LobbyRole) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_state::<GameState>()
+        .insert_resource(role)
+        .init_resource::<NetSettings>()
+        .init_resource::<JoinForm>()
+        .add_system(start_matchbox_socket.in_schedule(OnEnter(GameState::Matchmaking)))
+        .add_system(wait_for_players.in_set(OnUpdate(GameState::Matchmaking)));
+    app.world.spawn(Nostr {
+        keys: nostr.keys.clone(),
+        relays: nostr.relays.clone(),
+        relay: nostr.relay.clone(),
+    });
+    app.world
+        .resource_mut::<NextState<GameState>>()
+        .set(GameState::Matchmaking);
+    app.update();
+    app
+}
+
+fn socket(app: &mut App) -> Mut<MatchboxSocket<MultipleChannels>> {
+    app.world.resource_mut::<MatchboxSocket<MultipleChannels>>()
+}
+
+fn room(app: &mut App) -> Mut<LobbyRoom> {
+    app.world.resource_mut::<LobbyRoom>()
+}
+
+fn in_game(app: &App) -> bool {
+    app.world.resource::<State<GameState>>().0 == GameState::InGame
+}
+
+async fn within<T>(what: &str, future: impl Future<Output = T>) -> T {
+    tokio::time::timeout(TIMEOUT, future)
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {what}"))
+}
+
+/// Runs both games until `done`
+async fn until(host: &mut App, joiner: &mut App, mut done: impl FnMut(&mut App, &mut App) -> bool) {
+    while !done(host, joiner) {
+        host.update();
+        joiner.update();
+        tokio::time::sleep(POLL).await;
+    }
+}
+
+fn running(app: &mut App) -> bool {
+    let mut session = app.world.resource_mut::<Session<GgrsConfig>>();
+    let Session::P2PSession(session) = session.as_mut() else {
+        panic!("expected a P2P session");
+    };
+    session.poll_remote_clients();
+    session.current_state() == SessionState::Running
+}
+
+#[test]
+fn two_clients_list_join_and_start_a_session() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let relay = start_relay().await;
+        let host = client(&relay);
+        let joiner = client(&relay);
+        let mut host_app = client_app(&host, LobbyRole::Host);
+
+        // listing
+        let hosted = HostedGame::new(&host, Listing::new("offline".to_string()), false, None);
+        let listing = listing_builder(&host, &hosted)
+            .to_event(&host.keys)
+            .unwrap();
+        let publisher = relays::connect(&host.keys, &host.relays).await;
+        publisher
+            .send_msg(ClientMessage::new_event(listing))
+            .await
+            .unwrap();
+        publisher.disconnect().await.ok();
+
+        let browser = relays::connect(&joiner.keys, &joiner.relays).await;
+        let events = within(
+            "the listing",
+            browser.get_events_of(vec![listing_filter()], Some(TIMEOUT)),
+        )
+        .await
+        .unwrap();
+        browser.disconnect().await.ok();
+        let game = events
+            .iter()
+            .find_map(|event| parse_listing(event, &relay))
+            .expect("the joiner sees the listing");
+        assert_eq!(game.listing, hosted.listing);
+        assert_eq!(game.relay, relay);
+
+        // joining
+        let mut joiner_app = client_app(&joiner, LobbyRole::Joiner);
+        let host_key = XOnlyPublicKey::from_bech32(&game.peer).unwrap();
+        assert_eq!(host_key, host.keys.public_key());
+        request_connection(joiner.keys.clone(), joiner.relays.clone(), host_key, None).await;
+
+        let host_peer = PeerId(host.keys.public_key());
+        let joiner_peer = PeerId(joiner.keys.public_key());
+        within(
+            "the host to see the joiner",
+            until(&mut host_app, &mut joiner_app, |host_app, _| {
+                socket(host_app)
+                    .connected_peers()
+                    .any(|peer| peer == joiner_peer)
+            }),
+        )
+        .await;
+
+        // the room, the way players click through it
+        room(&mut host_app)
+            .actions
+            .push(RoomAction::Accept(joiner_peer));
+        within(
+            "the host to let us in",
+            until(&mut host_app, &mut joiner_app, |_, joiner_app| {
+                room(joiner_app).accepted == Some(host_peer)
+            }),
+        )
+        .await;
+        room(&mut host_app).actions.push(RoomAction::Ready(true));
+        room(&mut joiner_app).actions.push(RoomAction::Ready(true));
+        within(
+            "both to go in-game",
+            until(&mut host_app, &mut joiner_app, |host_app, joiner_app| {
+                in_game(host_app) && in_game(joiner_app)
+            }),
+        )
+        .await;
+        assert_eq!(
+            *host_app.world.resource::<NetSettings>(),
+            *joiner_app.world.resource::<NetSettings>()
+        );
+        // nothing leaked into chat
+        assert!(socket(&mut joiner_app)
+            .channel(CHAT_CHANNEL)
+            .receive()
+            .is_empty());
+
+        // session start
+        within("both sessions to synchronize", async {
+            while !(running(&mut host_app) && running(&mut joiner_app)) {
+                tokio::time::sleep(POLL).await;
+            }
+        })
+        .await;
+    });
+}
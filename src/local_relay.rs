@@ -0,0 +1,333 @@
+//! A tiny in-memory Nostr relay, enough for the whole game to run without the
+//! internet: listings, DMs and the WebRTC signalling, profiles and results.
+//! Nothing is kept once it stops.
+//!
+//! Shared by the `local_relay` binary and the tests, so it only uses what both
+//! have: no bevy, nothing from the game.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use nostr_sdk::serde_json::{self, json, Value};
+use nostr_sdk::{Event, Timestamp};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7447";
+/// The oldest events go once there are more than this
+const MAX_EVENTS: usize = 10_000;
+/// Live events a slow connection may fall behind by before it misses some
+const LIVE_BACKLOG: usize = 1024;
+
+fn kind(event: &Value) -> u64 {
+    event["kind"].as_u64().unwrap_or_default()
+}
+
+fn created_at(event: &Value) -> u64 {
+    event["created_at"].as_u64().unwrap_or_default()
+}
+
+fn tag_values<'a>(event: &'a Value, name: &'a str) -> impl Iterator<Item = &'a str> {
+    event["tags"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|tag| tag.as_array())
+        .filter(move |tag| tag.first().and_then(Value::as_str) == Some(name))
+        .filter_map(|tag| tag.get(1).and_then(Value::as_str))
+}
+
+fn ephemeral(kind: u64) -> bool {
+    (20_000..30_000).contains(&kind)
+}
+
+/// What makes two events the same slot, for the replaceable kinds
+fn replaces(kind: u64, event: &Value) -> Option<(String, u64, String)> {
+    let pubkey = event["pubkey"].as_str()?.to_string();
+    match kind {
+        0 | 3 | 10_000..=19_999 => Some((pubkey, kind, String::new())),
+        30_000..=39_999 => {
+            let d = tag_values(event, "d").next().unwrap_or_default();
+            Some((pubkey, kind, d.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// NIP-40
+fn expired(event: &Value, now: u64) -> bool {
+    tag_values(event, "expiration")
+        .next()
+        .and_then(|expiration| expiration.parse::<u64>().ok())
+        .map_or(false, |expiration| expiration <= now)
+}
+
+fn any_prefix(wanted: &Value, value: &Value) -> bool {
+    let (Some(wanted), Some(value)) = (wanted.as_array(), value.as_str()) else {
+        return false;
+    };
+    wanted
+        .iter()
+        .filter_map(Value::as_str)
+        .any(|prefix| value.starts_with(prefix))
+}
+
+/// NIP-01 filter matching. Fields we don't know about, like `search`, are
+/// ignored rather than matching nothing.
+pub fn matches(filter: &Value, event: &Value) -> bool {
+    let Some(filter) = filter.as_object() else {
+        return false;
+    };
+    filter.iter().all(|(field, wanted)| match field.as_str() {
+        "ids" => any_prefix(wanted, &event["id"]),
+        "authors" => any_prefix(wanted, &event["pubkey"]),
+        "kinds" => wanted
+            .as_array()
+            .map_or(false, |kinds| kinds.contains(&event["kind"])),
+        "since" => wanted
+            .as_u64()
+            .map_or(true, |since| created_at(event) >= since),
+        "until" => wanted
+            .as_u64()
+            .map_or(true, |until| created_at(event) <= until),
+        tag if tag.starts_with('#') => {
+            let Some(wanted) = wanted.as_array() else {
+                return false;
+            };
+            tag_values(event, &tag[1..])
+                .any(|value| wanted.iter().any(|wanted| wanted.as_str() == Some(value)))
+        }
+        _ => true,
+    })
+}
+
+#[derive(Default)]
+pub struct Store {
+    /// Oldest first
+    events: Vec<Value>,
+}
+
+impl Store {
+    /// Keeps `event` unless it's ephemeral, a duplicate or older than what it
+    /// would replace. Returns whether it's new, new ones go out to subscribers.
+    pub fn insert(&mut self, event: Value) -> bool {
+        let kind = kind(&event);
+        if ephemeral(kind) {
+            return true;
+        }
+        if self.events.iter().any(|stored| stored["id"] == event["id"]) {
+            return false;
+        }
+        if let Some(slot) = replaces(kind, &event) {
+            let newer = self.events.iter().any(|stored| {
+                replaces(self::kind(stored), stored).as_ref() == Some(&slot)
+                    && created_at(stored) > created_at(&event)
+            });
+            if newer {
+                return false;
+            }
+            self.events
+                .retain(|stored| replaces(self::kind(stored), stored).as_ref() != Some(&slot));
+        }
+        self.events.push(event);
+        if self.events.len() > MAX_EVENTS {
+            self.events.remove(0);
+        }
+        true
+    }
+
+    /// Newest first, each filter up to its own limit
+    pub fn query(&self, filters: &[Value]) -> Vec<Value> {
+        let now = Timestamp::now().as_u64();
+        let mut seen = HashSet::new();
+        let mut found = Vec::new();
+        for filter in filters {
+            let limit = filter["limit"].as_u64().map_or(usize::MAX, |l| l as usize);
+            let matching = self
+                .events
+                .iter()
+                .rev()
+                .filter(|event| !expired(event, now) && matches(filter, event))
+                .take(limit);
+            for event in matching {
+                if seen.insert(event["id"].to_string()) {
+                    found.push(event.clone());
+                }
+            }
+        }
+        found.sort_by_key(|event| std::cmp::Reverse(created_at(event)));
+        found
+    }
+}
+
+/// Accepts connections until the listener fails
+pub async fn serve(listener: TcpListener) {
+    let store = Arc::new(Mutex::new(Store::default()));
+    let (live, _) = broadcast::channel(LIVE_BACKLOG);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("local relay stopped accepting: {e}");
+                return;
+            }
+        };
+        let store = store.clone();
+        let live = live.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection(stream, store, live).await {
+                println!("relay connection closed: {e}");
+            }
+        });
+    }
+}
+
+async fn connection(
+    stream: TcpStream,
+    store: Arc<Mutex<Store>>,
+    live: broadcast::Sender<Arc<Value>>,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let (mut sink, mut source) = tokio_tungstenite::accept_async(stream).await?.split();
+    let mut incoming = live.subscribe();
+    let mut subscriptions: HashMap<String, Vec<Value>> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            message = source.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Ping(ping))) => {
+                        sink.send(Message::Pong(ping)).await?;
+                        continue;
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e),
+                };
+                let replies = handle(&text, &store, &live, &mut subscriptions).await;
+                for reply in replies {
+                    sink.send(Message::Text(reply.to_string())).await?;
+                }
+            }
+            event = incoming.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        eprintln!("relay connection fell behind, {missed} events missed");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                for (id, filters) in &subscriptions {
+                    if filters.iter().any(|filter| matches(filter, &event)) {
+                        let reply = json!(["EVENT", id, event.as_ref()]);
+                        sink.send(Message::Text(reply.to_string())).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Answers one client message
+async fn handle(
+    text: &str,
+    store: &Mutex<Store>,
+    live: &broadcast::Sender<Arc<Value>>,
+    subscriptions: &mut HashMap<String, Vec<Value>>,
+) -> Vec<Value> {
+    let Ok(Value::Array(message)) = serde_json::from_str::<Value>(text) else {
+        return vec![json!(["NOTICE", "invalid: not a json array"])];
+    };
+    match (message.first().and_then(Value::as_str), message.get(1)) {
+        (Some("EVENT"), Some(event)) => {
+            let id = event["id"].clone();
+            let valid = serde_json::from_value::<Event>(event.clone())
+                .map_err(|e| e.to_string())
+                .and_then(|parsed| parsed.verify().map_err(|e| e.to_string()));
+            if let Err(e) = valid {
+                return vec![json!(["OK", id, false, format!("invalid: {e}")])];
+            }
+            if store.lock().await.insert(event.clone()) {
+                // nobody listening is fine
+                live.send(Arc::new(event.clone())).ok();
+            }
+            vec![json!(["OK", id, true, ""])]
+        }
+        (Some("REQ"), Some(Value::String(id))) => {
+            let filters = message[2..].to_vec();
+            let mut replies: Vec<Value> = store
+                .lock()
+                .await
+                .query(&filters)
+                .into_iter()
+                .map(|event| json!(["EVENT", id, event]))
+                .collect();
+            replies.push(json!(["EOSE", id]));
+            subscriptions.insert(id.clone(), filters);
+            replies
+        }
+        (Some("CLOSE"), Some(Value::String(id))) => {
+            subscriptions.remove(id);
+            Vec::new()
+        }
+        _ => vec![json!(["NOTICE", "unsupported message"])],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag, TagKind};
+
+    fn event(keys: &Keys, kind: u64, tags: &[Tag]) -> Value {
+        let event = EventBuilder::new(Kind::from(kind), "", tags)
+            .to_event(keys)
+            .unwrap();
+        serde_json::to_value(event).unwrap()
+    }
+
+    #[test]
+    fn filters_match_like_nip01() {
+        let keys = Keys::generate();
+        let other = Keys::generate().public_key().to_string();
+        let dm = event(&keys, 4, &[Tag::Generic(TagKind::P, vec![other.clone()])]);
+
+        assert!(matches(&json!({"kinds": [4], "#p": [other]}), &dm));
+        let author = keys.public_key().to_string();
+        assert!(matches(&json!({"authors": [&author[..8]]}), &dm));
+        assert!(!matches(&json!({"kinds": [1]}), &dm));
+        assert!(!matches(
+            &json!({"#p": [keys.public_key().to_string()]}),
+            &dm
+        ));
+        assert!(!matches(&json!({"since": created_at(&dm) + 1}), &dm));
+    }
+
+    #[test]
+    fn replaceable_events_keep_the_latest() {
+        let keys = Keys::generate();
+        let d = |id: &str| Tag::Generic(TagKind::Custom("d".to_string()), vec![id.to_string()]);
+        let mut store = Store::default();
+
+        let mut first = event(&keys, 30_420, &[d("game")]);
+        first["created_at"] = json!(created_at(&first) - 10);
+        let second = event(&keys, 30_420, &[d("game")]);
+        let other_game = event(&keys, 30_420, &[d("other")]);
+        assert!(store.insert(first.clone()));
+        assert!(store.insert(second.clone()));
+        assert!(store.insert(other_game));
+        // older than what's there now
+        assert!(!store.insert(first));
+        assert!(!store.insert(second.clone()));
+
+        let found = store.query(&[json!({"kinds": [30_420], "#d": ["game"]})]);
+        assert_eq!(found, vec![second]);
+
+        // ephemeral events are passed on, never stored
+        assert!(store.insert(event(&keys, 20_001, &[])));
+        assert!(store.query(&[json!({"kinds": [20_001]})]).is_empty());
+    }
+}
@@ -3,40 +3,93 @@ use bevy::render::camera::ScalingMode;
 use bevy::{prelude::*, window::Window};
 use bevy_asset_loader::prelude::*;
 use bevy_egui::egui::{Pos2, TextEdit};
-use bevy_ggrs::ggrs::PlayerType;
 use bevy_ggrs::{ggrs, GGRSPlugin, GGRSSchedule, RollbackIdProvider, Session};
 use bevy_matchbox_nostr::prelude::*;
 use bevy_mod_simplest_healthbar::{HealthBar, HealthBarPlugin};
 use components::*;
-use log::Level;
 use nostr_sdk::prelude::{FromBech32, ToBech32};
 use nostr_sdk::secp256k1::XOnlyPublicKey;
-use nostr_sdk::{
-    serde_json, Client, ClientMessage, EventBuilder, Filter, Keys, RelayPoolNotification, Tag,
-    Timestamp,
-};
+use nostr_sdk::{serde_json, EventId, Keys};
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use wasm_bindgen_futures::spawn_local;
 mod components;
 use spells::*;
 mod spells;
 use input::*;
 mod input;
+use fixed::*;
+mod fixed;
+use render::*;
+mod render;
+use network::*;
+mod network;
+use net_stats::*;
+mod net_stats;
+use reconnect::*;
+mod reconnect;
+mod storage;
+use identity::*;
+mod identity;
+mod nip07;
+use time_sync::*;
+mod time_sync;
+use profiles::*;
+mod profiles;
+use lobby::*;
+mod lobby;
+use browser::*;
+mod browser;
+use invite::*;
+mod invite;
+use quick_match::*;
+mod quick_match;
+use relays::*;
+mod relays;
+use results::*;
+mod results;
+use ratings::*;
+mod ratings;
+use history::*;
+mod history;
+use chat::*;
+mod chat;
+use friends::*;
+mod friends;
+use rematch::*;
+mod rematch;
+use tasks::*;
+mod tasks;
+use game_server::*;
+mod forwarding;
+mod game_server;
+mod socket_builder;
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod local_match_tests;
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod local_relay;
+
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
 
 pub fn main() {
-    console_log::init_with_level(Level::Warn).expect("error initializing log");
+    // natively bevy's own log plugin prints these
+    #[cfg(target_arch = "wasm32")]
+    console_log::init_with_level(log::Level::Warn).expect("error initializing log");
 
     let mut app = App::new();
 
     GGRSPlugin::<GgrsConfig>::new()
         .with_input_system(input)
-        .register_rollback_component::<Transform>()
-        // .register_rollback_component::<Target>()
-        // .register_rollback_component::<BulletReady>()
-        // .register_rollback_component::<MoveDir>()
+        .register_rollback_component::<Position>()
+        .register_rollback_component::<Target>()
+        .register_rollback_component::<BulletReady>()
+        .register_rollback_component::<MoveDir>()
+        .register_rollback_component::<Bullet>()
+        .register_rollback_component::<Player>()
+        .register_rollback_component::<Health>()
+        .register_rollback_component::<PlayerStats>()
+        .register_rollback_resource::<FrameCount>()
+        .register_rollback_resource::<FallenStats>()
         .build(&mut app);
 
     app.add_state::<GameState>()
@@ -62,38 +115,248 @@ pub fn main() {
                 // to automatically spawn bars on stuff with Health and a Transform
                 .automatic_bar_creation(true),
         )
-        .add_system(menu.run_if(in_state(GameState::Menu)))
+        .add_systems(
+            (
+                check_relays,
+                pick_signalling_relay.before(menu),
+                menu,
+                identity_panel,
+                relay_settings,
+                prune_games,
+                quick_match_panel,
+                quick_match_search.run_if(resource_exists::<QuickMatchSearch>()),
+                leaderboard_panel,
+                stats_panel,
+                friends_panel,
+            )
+                .in_set(OnUpdate(GameState::Menu)),
+        )
+        .add_systems(
+            (refresh_games, refresh_leaderboard, forget_challenge)
+                .in_schedule(OnEnter(GameState::Menu)),
+        )
+        .add_systems((
+            fetch_profiles,
+            load_avatars,
+            publish_presence,
+            listen_friends,
+        ))
         .insert_resource(ClearColor(Color::rgb(0.0, 0.0, 0.0)))
-        .add_system(start_matchbox_socket.in_schedule(OnEnter(GameState::Matchmaking)))
+        .add_systems(
+            (start_matchbox_socket, open_join_gate).in_schedule(OnEnter(GameState::Matchmaking)),
+        )
+        .add_systems(
+            (close_join_gate, close_listing, leave_matchmaking)
+                .in_schedule(OnExit(GameState::Matchmaking)),
+        )
+        .add_system(
+            gate_peers.run_if(
+                resource_exists::<JoinGate>()
+                    .and_then(resource_exists::<MatchboxSocket<MultipleChannels>>()),
+            ),
+        )
+        .add_system(read_page_invite.in_schedule(OnEnter(GameState::AssetLoading)))
+        .add_system(
+            quick_match_room.before(wait_for_players).run_if(
+                resource_exists::<QuickMatch>()
+                    .and_then(resource_exists::<LobbyRoom>())
+                    .and_then(resource_exists::<MatchboxSocket<MultipleChannels>>())
+                    .and_then(in_state(GameState::Matchmaking)),
+            ),
+        )
+        .add_system(
+            accept_challenger.before(wait_for_players).run_if(
+                resource_exists::<ChallengeSent>()
+                    .and_then(resource_exists::<LobbyRoom>())
+                    .and_then(resource_exists::<MatchboxSocket<MultipleChannels>>())
+                    .and_then(in_state(GameState::Matchmaking)),
+            ),
+        )
         .add_systems((
             wait_for_players.run_if(
-                resource_exists::<MatchboxSocket<SingleChannel>>()
+                resource_exists::<MatchboxSocket<MultipleChannels>>()
+                    .and_then(in_state(GameState::Matchmaking)),
+            ),
+            lobby_panel.after(wait_for_players).run_if(
+                resource_exists::<MatchboxSocket<MultipleChannels>>()
                     .and_then(in_state(GameState::Matchmaking)),
             ),
             spawn_players.in_schedule(OnEnter(GameState::InGame)),
         ))
-        .add_system(log_ggrs_events.in_set(OnUpdate(GameState::InGame)))
+        .add_system(
+            game_server_panel
+                .run_if(resource_exists::<LobbyRoom>().and_then(in_state(GameState::Matchmaking))),
+        )
+        .add_systems(
+            (
+                connect_to_server.run_if(
+                    resource_exists::<ServerConnection>()
+                        .and_then(resource_exists::<MatchboxSocket<MultipleChannels>>())
+                        .and_then(resource_exists::<GgrsChannel>()),
+                ),
+                waiting_for_server_banner.run_if(resource_exists::<ServerConnection>()),
+            )
+                .in_set(OnUpdate(GameState::InGame)),
+        )
+        .add_system(
+            receive_chat.run_if(
+                resource_exists::<MatchboxSocket<MultipleChannels>>()
+                    .and_then(resource_exists::<LobbyRoom>()),
+            ),
+        )
+        .add_event::<OpponentLeft>()
+        .add_system(
+            hold_session
+                .in_base_set(CoreSet::First)
+                .after(bevy::time::TimeSystem)
+                .run_if(resource_exists::<Session<GgrsConfig>>()),
+        )
+        .add_system(release_session.in_base_set(CoreSet::PreUpdateFlush))
+        .add_systems(
+            (
+                handle_ggrs_events.run_if(resource_exists::<Session<GgrsConfig>>()),
+                // leaving after the match is over is fine
+                on_opponent_left
+                    .after(handle_ggrs_events)
+                    .run_if(not(resource_exists::<MatchOver>())),
+                await_reconnect.run_if(
+                    resource_exists::<AwaitingReconnect>()
+                        .and_then(resource_exists::<MatchboxSocket<MultipleChannels>>()),
+                ),
+                heartbeat_active_match.run_if(resource_exists::<ActiveMatch>()),
+                time_sync
+                    .after(handle_ggrs_events)
+                    .run_if(resource_exists::<Session<GgrsConfig>>()),
+                interruption_banner.run_if(not(resource_exists::<MatchOver>())),
+            )
+                .in_set(OnUpdate(GameState::InGame)),
+        )
+        .add_systems(
+            (
+                detect_match_end.run_if(
+                    resource_exists::<Session<GgrsConfig>>()
+                        .and_then(resource_exists::<PlayerKeys>())
+                        .and_then(not(resource_exists::<MatchOver>())),
+                ),
+                exchange_result.run_if(
+                    resource_exists::<MatchOver>()
+                        .and_then(resource_exists::<MatchboxSocket<MultipleChannels>>()),
+                ),
+                match_over_panel.run_if(resource_exists::<MatchOver>()),
+                start_rematch.after(exchange_result).run_if(
+                    resource_exists::<MatchOver>()
+                        .and_then(resource_exists::<GgrsChannel>())
+                        .and_then(resource_exists::<LocalPlayerHandle>()),
+                ),
+                record_match.run_if(
+                    resource_exists::<LocalPlayerHandle>()
+                        .and_then(resource_exists::<PlayerKeys>())
+                        .and_then(
+                            resource_added::<MatchOver>().or_else(resource_added::<Forfeit>()),
+                        ),
+                ),
+            )
+                .in_set(OnUpdate(GameState::InGame)),
+        )
+        .add_systems(
+            (remember_match, forget_quick_match, forget_challenge)
+                .in_schedule(OnEnter(GameState::InGame)),
+        )
+        .add_system(
+            refresh_listing
+                .run_if(resource_exists::<HostedGame>().and_then(in_state(GameState::Matchmaking))),
+        )
+        .add_systems(
+            (
+                check_relays,
+                fail_over_signalling.run_if(
+                    resource_exists::<HostedGame>().and_then(resource_exists::<LobbyRoom>()),
+                ),
+            )
+                .in_set(OnUpdate(GameState::Matchmaking)),
+        )
+        .add_systems(
+            (
+                teardown_match,
+                forget_match,
+                reset_time_sync,
+                forget_match_over,
+            )
+                .in_schedule(OnExit(GameState::InGame)),
+        )
+        .add_systems(
+            (
+                update_net_stats.run_if(resource_exists::<Session<GgrsConfig>>()),
+                net_stats_overlay.after(update_net_stats),
+            )
+                .in_set(OnUpdate(GameState::InGame)),
+        )
+        .add_systems((sync_transforms, flash_on_hit).in_set(OnUpdate(GameState::InGame)))
+        .add_systems(
+            (
+                game_chat,
+                emote_bubbles
+                    .after(sync_transforms)
+                    .run_if(resource_exists::<PlayerKeys>()),
+            )
+                .in_set(OnUpdate(GameState::InGame)),
+        )
+        .add_system(
+            player_name_tags
+                .after(sync_transforms)
+                .run_if(resource_exists::<PlayerKeys>().and_then(in_state(GameState::InGame))),
+        )
         .add_systems(
             (
+                count_frames.before(move_system),
                 move_system,
                 fire_bullets.after(move_system),
                 reload_bullet.after(fire_bullets),
                 move_bullet.after(fire_bullets),
                 kill_players.after(move_bullet).after(move_system),
                 update_health.after(kill_players),
+                record_state.after(update_health),
             )
                 .in_schedule(GGRSSchedule),
         )
         .insert_resource(GameName {
             name: String::new(),
+            mode: MODES[0].to_string(),
+            map: MAPS[0].to_string(),
+            region: REGIONS[0].to_string(),
+            private: false,
+            password: String::new(),
         })
         .insert_resource(GamesList(Arc::new(Mutex::new(Vec::new()))))
         .insert_resource(SearchGames { search: true })
+        .init_resource::<ControlScheme>()
+        .init_resource::<LobbyRole>()
+        .init_resource::<NetSettings>()
+        .init_resource::<FrameCount>()
+        .init_resource::<FallenStats>()
+        .init_resource::<SimSteps>()
+        .init_resource::<StateHistory>()
+        .init_resource::<NetStatsOverlay>()
+        .init_resource::<TimeSync>()
+        .init_resource::<ExtensionIdentity>()
+        .init_resource::<IdentityForm>()
+        .init_resource::<Profiles>()
+        .init_resource::<AvatarTextures>()
+        .init_resource::<RelayHealth>()
+        .init_resource::<RelayForm>()
+        .init_resource::<BrowserFilter>()
+        .init_resource::<LobbySubscription>()
+        .init_resource::<JoinForm>()
+        .init_resource::<Leaderboard>()
+        .insert_resource(MatchHistory::load())
+        .init_resource::<Chat>()
+        .insert_resource(Friends::load())
+        .insert_resource(GameServer::load())
         .run();
 }
 
 #[derive(States, Clone, Eq, PartialEq, Debug, Hash, Default)]
-enum GameState {
+pub enum GameState {
     #[default]
     AssetLoading,
     Menu,
@@ -102,11 +365,16 @@ enum GameState {
 }
 
 #[derive(Resource)]
-struct LocalPlayerHandle(usize);
+pub struct LocalPlayerHandle(pub usize);
 
 #[derive(Resource, Default, Debug)]
 pub struct GameName {
     pub name: String,
+    pub mode: String,
+    pub map: String,
+    pub region: String,
+    pub private: bool,
+    pub password: String,
 }
 
 #[derive(Resource, Default, Debug)]
@@ -131,7 +399,7 @@ pub struct ImageAssets {
 pub struct GgrsConfig;
 
 impl ggrs::Config for GgrsConfig {
-    // 4-directions + fire fits easily in a single byte
+    // button bits, direction bits and a quantized target, see `CustomInput`
     type Input = CustomInput;
     type State = u8;
     // Matchbox' WebRtcSocket addresses are called `PeerId`s
@@ -140,35 +408,116 @@ impl ggrs::Config for GgrsConfig {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Game {
-    pub name: String,
+    pub listing: Listing,
     pub created_by: String,
+    /// Key the host's socket signals with, differs from `created_by` when the
+    /// listing was signed by a NIP-07 extension
+    pub peer: String,
+    /// Relay the host's socket signals through, the joiner has to use it too
+    pub relay: String,
+    /// The listing event, it arrives once from every relay
+    pub id: EventId,
+    /// `d` tag, later events with the same one replace the listing
+    pub d: String,
+    pub created_at: u64,
+    /// NIP-40 expiration, unix seconds
+    pub expires_at: Option<u64>,
 }
 
 impl Game {
-    pub fn display_name(&self) -> String {
-        format!("Game Name: {}, Created by: {}", self.name, self.created_by)
+    pub fn display_name(&self, profiles: &mut Profiles) -> String {
+        format!(
+            "Game Name: {}, Created by: {}",
+            self.listing.name,
+            profiles.name_of_npub(&self.created_by)
+        )
+    }
+
+    pub fn host(&self) -> Option<XOnlyPublicKey> {
+        XOnlyPublicKey::from_bech32(&self.created_by).ok()
     }
 }
 
 fn create_nostr_key(mut commands: Commands) {
-    let keys = Keys::generate();
-    let relay = "wss://nostr.lu.ke".to_string();
-    //let relay = "ws://localhost:8080".to_string();
-    commands.spawn(Nostr { relay, keys });
+    let keys = load_or_create_keys();
+    // reloaded mid match, offer to go back in
+    if let Some(active) = ActiveMatch::load() {
+        let player = keys.public_key().to_bech32().unwrap();
+        if let (true, Some(opponent)) = (active.player == player, active.opponent()) {
+            commands.insert_resource(Rejoin {
+                opponent,
+                relay: active.relay.clone(),
+            });
+        }
+    }
+    let relays = load_relays();
+    let relay = relays[0].clone();
+    commands.spawn(Nostr {
+        relays,
+        relay,
+        keys,
+    });
 }
 
+/// Asks `reciever` over a nostr DM to open a WebRTC connection with us. The
+/// `secret` of a private or password protected game goes ahead of it.
+pub fn send_new_peer(nostr: &Nostr, reciever: XOnlyPublicKey, secret: Option<String>) {
+    let nostr_keys = nostr.keys.clone();
+    // the other socket only listens on the signalling relay, which might not
+    // be one of ours
+    let mut relays = nostr.relays.clone();
+    if !relays.contains(&nostr.relay) {
+        relays.push(nostr.relay.clone());
+    }
+
+    info!("connecting to nostr relays: {:?}", relays);
+
+    spawn(request_connection(nostr_keys, relays, reciever, secret));
+}
+
+/// DMs the host our key, their socket then starts the WebRTC handshake
+pub async fn request_connection(
+    nostr_keys: Keys,
+    relays: Vec<String>,
+    reciever: XOnlyPublicKey,
+    secret: Option<String>,
+) {
+    let pub_key = PeerId(nostr_keys.public_key());
+    let new_peer = PeerEvent::NewPeer(pub_key);
+    let new_peer = serde_json::to_string(&new_peer).expect("serializing request");
+
+    let client = relays::connect(&nostr_keys, &relays).await;
+    if let Some(join_secret) = secret {
+        let request = JoinRequest { join_secret };
+        let request = serde_json::to_string(&request).expect("serializing join request");
+        if let Err(e) = client.send_direct_msg(reciever, request).await {
+            warn!("failed to send the join request: {e}");
+        }
+    }
+    if let Err(e) = client.send_direct_msg(reciever, new_peer).await {
+        warn!("failed to send the connection request: {e}");
+    }
+    client.disconnect().await.unwrap();
+}
+
+#[allow(clippy::too_many_arguments)]
 fn menu(
+    mut commands: Commands,
     mut contexts: EguiContexts,
     window: Query<&Window>,
     mut next_state: ResMut<NextState<GameState>>,
-    nostr_query: Query<&Nostr>,
-    games_list: Res<GamesList>,
+    mut nostr_query: Query<&mut Nostr>,
+    mut browser: Browser,
     mut game_name: ResMut<GameName>,
-    mut search_games: ResMut<SearchGames>,
+    mut control_scheme: ResMut<ControlScheme>,
+    mut role: ResMut<LobbyRole>,
+    rejoin: Option<Res<Rejoin>>,
+    extension: Res<ExtensionIdentity>,
+    mut profiles: ResMut<Profiles>,
+    avatars: Res<AvatarTextures>,
+    mut join_form: ResMut<JoinForm>,
 ) {
-    let nostr = nostr_query.iter().next().unwrap();
-    let nostr_keys = nostr.keys.clone();
-    let relay = nostr.relay.clone();
+    let mut nostr = nostr_query.single_mut();
 
     let window = window.iter().next().unwrap();
     let screen_size = egui::Vec2::new(window.width(), window.height());
@@ -181,122 +530,137 @@ fn menu(
         .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
         .fixed_pos(pos)
         .show(contexts.ctx_mut(), |ui| {
+            if let Some(rejoin) = &rejoin {
+                ui.heading("You have a match in progress");
+                ui.horizontal(|ui| {
+                    if ui.button("Rejoin").clicked() {
+                        // the opponent waits on the relay we played through
+                        if !rejoin.relay.is_empty() {
+                            nostr.relay = rejoin.relay.clone();
+                        }
+                        send_new_peer(&nostr, rejoin.opponent, None);
+                        *role = LobbyRole::Joiner;
+                        next_state.set(GameState::Matchmaking);
+                    }
+                    if ui.button("Abandon").clicked() {
+                        ActiveMatch::clear();
+                        commands.remove_resource::<Rejoin>();
+                    }
+                });
+                ui.separator();
+            }
+
             ui.add(
                 TextEdit::singleline(&mut game_name.name)
                     .hint_text("Enter game name")
                     .id(egui::Id::new("game_name_input")),
             );
 
-            if ui.small_button("Create Game").clicked() && !game_name.name.is_empty() {
-                let game_name = game_name.name.clone();
-                let nostr_keys = nostr.keys.clone();
-                let relay = nostr.relay.clone();
-
-                info!("connecting to nostr relay: {:?}", relay);
-
-                //list game
-                spawn_local(async move {
-                    let tag = "matchbox-nostr-v1";
-                    let new_game = serde_json::to_string(&game_name).expect("serializing request");
-
-                    let broadcast_peer = ClientMessage::new_event(
-                        EventBuilder::new_text_note(new_game, &[Tag::Hashtag(tag.to_string())])
-                            .to_event(&nostr_keys)
-                            .unwrap(),
-                    );
-
-                    warn!("LIST GAME {:?}", broadcast_peer);
-
-                    let client = Client::new(&nostr_keys);
-                    #[cfg(target_arch = "wasm32")]
-                    client.add_relay(&relay).await.unwrap();
+            ui.horizontal(|ui| {
+                ui.label("Controls:");
+                ui.radio_value(
+                    control_scheme.as_mut(),
+                    ControlScheme::ClickToMove,
+                    "Click to move",
+                );
+                ui.radio_value(
+                    control_scheme.as_mut(),
+                    ControlScheme::Direct,
+                    "WASD / arrows",
+                );
+            });
+
+            ui.horizontal(|ui| {
+                option_combo(ui, "Mode", &mut game_name.mode, MODES);
+                option_combo(ui, "Map", &mut game_name.map, MAPS);
+                option_combo(ui, "Region", &mut game_name.region, REGIONS);
+            });
+
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut game_name.private, "Private (invite only)");
+                ui.add(
+                    TextEdit::singleline(&mut game_name.password)
+                        .hint_text("Password (optional)")
+                        .password(true),
+                );
+            });
 
-                    client.connect().await;
-                    client.send_msg(broadcast_peer).await.unwrap();
-                    client.disconnect().await.unwrap();
-                });
+            if ui.small_button("Create Game").clicked() && !game_name.name.is_empty() {
+                let listing = Listing {
+                    mode: game_name.mode.clone(),
+                    map: game_name.map.clone(),
+                    region: game_name.region.clone(),
+                    ..Listing::new(game_name.name.clone())
+                };
+                let password = Some(game_name.password.clone()).filter(|p| !p.is_empty());
+                let hosted = HostedGame::new(&nostr, listing, game_name.private, password);
+                publish_listing(&nostr, &extension, &hosted);
+                commands.insert_resource(hosted);
+                *role = LobbyRole::Host;
                 next_state.set(GameState::Matchmaking);
             }
 
-            if search_games.search {
-                let games_handle = games_list.0.clone();
-                info!("connecting to nostr relay: {:?}", relay);
-                spawn_local(async move {
-                    let tag = "matchbox-nostr-v1";
-
-                    let client = Client::new(&nostr_keys);
-                    #[cfg(target_arch = "wasm32")]
-                    client.add_relay(&relay).await.unwrap();
-
-                    client.connect().await;
-                    //send sub message
-
-                    let subscription = Filter::new()
-                        .since(Timestamp::now() - Duration::from_secs(30))
-                        .hashtag(tag);
-
-                    client.subscribe(vec![subscription]).await;
-
-                    client
-                        .handle_notifications(move |notification| {
-                            let games = games_handle.clone();
-                            async move {
-                                if let RelayPoolNotification::Event(_url, event) = notification {
-                                    info!("{:?}", event.content);
-                                    let game_name: String = serde_json::from_str(&event.content)
-                                        .expect("deserializing request");
-
-                                    let mut games_lock = games.lock().unwrap();
-
-                                    let game = Game {
-                                        name: game_name,
-                                        created_by: event.pubkey.to_bech32().unwrap(),
-                                    };
-                                    games_lock.push(game);
-                                }
-                                Ok(())
-                            }
-                        })
-                        .await
-                        .unwrap();
-                });
-                search_games.search = false;
+            if browser.search_games.search {
+                subscribe_listings(&nostr, &browser.games, &browser.subscription);
+                browser.search_games.search = false;
             }
 
-            let games_lock = games_list.0.lock().unwrap();
             ui.separator();
-            ui.label("searching for games...");
-            ui.separator();
-            if games_lock.is_empty() {
-                ui.label("No games found, please wait or create a game.");
+            ui.horizontal(|ui| {
+                ui.add(
+                    TextEdit::singleline(&mut join_form.invite).hint_text("Paste an invite link"),
+                );
+                let invite = Invite::parse(&join_form.invite);
+                if ui
+                    .add_enabled(invite.is_some(), egui::Button::new("Join"))
+                    .clicked()
+                {
+                    let invite = invite.unwrap();
+                    nostr.relay = invite.relay.clone();
+                    send_new_peer(&nostr, invite.host, Some(invite.token));
+                    join_form.invite.clear();
+                    join_form.error = None;
+                    *role = LobbyRole::Joiner;
+                    next_state.set(GameState::Matchmaking);
+                }
+            });
+
+            if let Some(game) = join_form.password_for.clone() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("Password for {}:", game.listing.name));
+                    ui.add(TextEdit::singleline(&mut join_form.password).password(true));
+                    if ui.button("Join").clicked() {
+                        let secret = std::mem::take(&mut join_form.password);
+                        let reciever = XOnlyPublicKey::from_bech32(&game.peer).unwrap();
+                        nostr.relay = game.relay.clone();
+                        send_new_peer(&nostr, reciever, Some(secret));
+                        join_form.password_for = None;
+                        join_form.error = None;
+                        *role = LobbyRole::Joiner;
+                        next_state.set(GameState::Matchmaking);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        join_form.password_for = None;
+                        join_form.password.clear();
+                    }
+                });
             }
 
-            for game in games_lock.iter() {
-                let list_game = format!("GAME NAME: {} CREATED BY: {}", game.name, game.created_by);
-                if ui.button(list_game).clicked() {
-                    //send nostr dm with peer id to game creator
-                    let reciever = XOnlyPublicKey::from_bech32(game.clone().created_by).unwrap();
-
-                    let nostr_keys = nostr.keys.clone();
-                    let relay = nostr.relay.clone();
-
-                    info!("connecting to nostr relay: {:?}", relay);
-
-                    //list game
-                    spawn_local(async move {
-                        let pub_key = PeerId(nostr_keys.public_key());
-                        let new_peer = PeerEvent::NewPeer(pub_key);
-                        let new_peer =
-                            serde_json::to_string(&new_peer).expect("serializing request");
-
-                        let client = Client::new(&nostr_keys);
-                        #[cfg(target_arch = "wasm32")]
-                        client.add_relay(&relay).await.unwrap();
+            if let Some(error) = &join_form.error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
 
-                        client.connect().await;
-                        client.send_direct_msg(reciever, new_peer).await.unwrap();
-                        client.disconnect().await.unwrap();
-                    });
+            ui.separator();
+            if let Some(game) = game_browser(ui, &mut browser, &mut profiles, &avatars) {
+                if game.listing.password {
+                    join_form.password_for = Some(game);
+                } else {
+                    //send nostr dm with peer id to game creator
+                    let reciever = XOnlyPublicKey::from_bech32(game.clone().peer).unwrap();
+                    nostr.relay = game.relay.clone();
+                    send_new_peer(&nostr, reciever, None);
+                    join_form.error = None;
+                    *role = LobbyRole::Joiner;
                     next_state.set(GameState::Matchmaking);
                 }
             }
@@ -352,74 +716,102 @@ fn spawn_players(
     mut commands: Commands,
     mut rip: ResMut<RollbackIdProvider>,
     images: Res<ImageAssets>,
+    snapshot: Option<Res<MatchSnapshot>>,
 ) {
     let mut camera_bundle = Camera2dBundle::default();
     camera_bundle.projection.scaling_mode = ScalingMode::FixedVertical(10.);
     commands.spawn((camera_bundle, BarCamera));
 
+    let players = spawn_fighters(&mut commands, &mut rip, &images);
+
+    // rejoining a match that was already running
+    if let Some(snapshot) = snapshot {
+        restore_snapshot(&mut commands, &mut rip, &images, &snapshot, &players);
+        commands.remove_resource::<MatchSnapshot>();
+    }
+}
+
+/// Both players at their starting spots, ordered by handle
+pub fn spawn_fighters(
+    commands: &mut Commands,
+    rip: &mut RollbackIdProvider,
+    images: &ImageAssets,
+) -> [Entity; 2] {
     let p1_rotation = Quat::from_rotation_y(std::f32::consts::PI);
 
     //player 1
-    let p1_position = Vec2::new(-5.0, 0.0);
-    commands.spawn((
-        Player {
-            handle: 0,
-            moving: false,
-        },
-        MoveDir(Vec2::X),
-        BulletReady { ready: true },
-        Target::default(),
-        rip.next(),
-        Health {
-            current: 21,
-            max: 21,
-        },
-        HealthBar {
-            offset: Vec2::new(0., 30.),
-            size: 20.,
-            color: Color::GREEN,
-        },
-        SpriteBundle {
-            sprite: Sprite {
-                custom_size: Some(Vec2::new(1., 1.)),
+    let p1_position = FixedVec2::from_f32(-5.0, 0.0);
+    let p1 = commands
+        .spawn((
+            Player {
+                handle: 0,
+                moving: false,
+            },
+            Position(p1_position),
+            MoveDir(FixedVec2::X),
+            BulletReady { ready: true },
+            Target::default(),
+            rip.next(),
+            Health {
+                current: 21,
+                max: 21,
+            },
+            HitFlash::new(21),
+            PlayerStats::default(),
+            HealthBar {
+                offset: Vec2::new(0., 30.),
+                size: 20.,
+                color: Color::GREEN,
+            },
+            SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::new(1., 1.)),
+                    ..Default::default()
+                },
+                texture: images.player_1.clone(),
+                transform: Transform::from_translation(p1_position.to_vec2().extend(0.0))
+                    .with_rotation(p1_rotation),
                 ..Default::default()
             },
-            texture: images.player_1.clone(),
-            transform: Transform::from_xyz(p1_position.x, p1_position.y, 0.0)
-                .with_rotation(p1_rotation),
-            ..Default::default()
-        },
-    ));
+        ))
+        .id();
     //player 2
-    let p2_position = Vec2::new(5.0, 0.0);
-    commands.spawn((
-        Player {
-            handle: 1,
-            moving: false,
-        },
-        MoveDir(-Vec2::X),
-        BulletReady { ready: true },
-        Target::default(),
-        rip.next(),
-        Health {
-            current: 21,
-            max: 21,
-        },
-        HealthBar {
-            offset: Vec2::new(0., 30.),
-            size: 20.,
-            color: Color::GREEN,
-        },
-        SpriteBundle {
-            sprite: Sprite {
-                custom_size: Some(Vec2::new(1., 1.)),
+    let p2_position = FixedVec2::from_f32(5.0, 0.0);
+    let p2 = commands
+        .spawn((
+            Player {
+                handle: 1,
+                moving: false,
+            },
+            Position(p2_position),
+            MoveDir(FixedVec2::NEG_X),
+            BulletReady { ready: true },
+            Target::default(),
+            rip.next(),
+            Health {
+                current: 21,
+                max: 21,
+            },
+            HitFlash::new(21),
+            PlayerStats::default(),
+            HealthBar {
+                offset: Vec2::new(0., 30.),
+                size: 20.,
+                color: Color::GREEN,
+            },
+            SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::new(1., 1.)),
+                    ..Default::default()
+                },
+                texture: images.player_2.clone(),
+                transform: Transform::from_translation(p2_position.to_vec2().extend(0.0)),
                 ..Default::default()
             },
-            texture: images.player_2.clone(),
-            transform: Transform::from_xyz(p2_position.x, p2_position.y, 0.0),
-            ..Default::default()
-        },
-    ));
+        ))
+        .id();
+
+    [p1, p2]
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -429,85 +821,6 @@ pub enum PeerEvent {
     NewPeer(PeerId),
 }
 
-fn start_matchbox_socket(mut commands: Commands, nostr_query: Query<&Nostr>) {
-    // let room_url = "ws://localhost:8080";
-    let nostr = nostr_query.iter().next().unwrap();
-
-    warn!("connecting to nostr relay: {:?}", nostr.relay);
-    warn!("pubkey: {:?}", nostr.keys.public_key());
-    commands.open_socket(
-        WebRtcSocketBuilder::new(nostr.relay.to_owned(), nostr.keys.clone())
-            .add_channel(ChannelConfig::ggrs()),
-    );
-}
-
-fn wait_for_players(
-    mut commands: Commands,
-    mut socket: ResMut<MatchboxSocket<SingleChannel>>,
-    mut next_state: ResMut<NextState<GameState>>,
-    mut contexts: EguiContexts,
-    window: Query<&Window>,
-) {
-    let window = window.iter().next().unwrap();
-    let screen_size = egui::Vec2::new(window.width(), window.height());
-    let screen_center = screen_size / 2.0;
-    let pos = Pos2::new(screen_center.x, screen_center.y / 2.0);
-
-    egui::Window::new("web21")
-        .resizable(false)
-        .collapsible(false)
-        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
-        .fixed_pos(pos)
-        .show(contexts.ctx_mut(), |ui| {
-            ui.heading("Waiting for players...");
-        });
-    // regularly call update_peers to update the list of connected peers
-    for (peer, new_state) in socket.update_peers() {
-        // you can also handle the specific dis(connections) as they occur:
-        match new_state {
-            PeerState::Connected => info!("peer {peer:?} connected"),
-            PeerState::Disconnected => info!("peer {peer:?} disconnected"),
-        }
-    }
-
-    let connected_peers = socket.connected_peers().count();
-    let remaining = 2 - (connected_peers + 1);
-
-    if remaining > 0 {
-        return;
-    }
-
-    info!("All peers have joined, going in-game");
-    let players = socket.players();
-
-    // create a GGRS P2P session
-    let mut session_builder = ggrs::SessionBuilder::<GgrsConfig>::new()
-        .with_num_players(2)
-        .with_input_delay(8);
-
-    for (i, player) in players.into_iter().enumerate() {
-        if player == PlayerType::Local {
-            info!("adding local player: {:?}", i);
-            commands.insert_resource(LocalPlayerHandle(i));
-        }
-
-        session_builder = session_builder
-            .add_player(player, i)
-            .expect("failed to add player");
-    }
-    info!("ggrs session started: {:?}", session_builder);
-    // move the channel out of the socket (required because GGRS takes ownership of it)
-    let socket = socket.take_channel(0).unwrap();
-
-    // start the GGRS session
-    let ggrs_session = session_builder
-        .start_p2p_session(socket)
-        .expect("failed to start session");
-
-    commands.insert_resource(bevy_ggrs::Session::P2PSession(ggrs_session));
-    next_state.set(GameState::InGame);
-}
-
 // fn respawn_players(
 //     mut commands: Commands,
 //     player_query: Query<(Entity, &Player), (With<Despawned>, Without<Bullet>)>,
@@ -526,16 +839,13 @@ fn wait_for_players(
 //     }
 // }
 
-fn log_ggrs_events(mut session: ResMut<Session<GgrsConfig>>) {
-    match session.as_mut() {
-        Session::P2PSession(s) => {
-            for event in s.events() {
-                info!("GGRS Event: {:?}", event);
+/// Picks one of `options` for a listing field
+fn option_combo(ui: &mut egui::Ui, label: &str, value: &mut String, options: &[&str]) {
+    egui::ComboBox::from_label(label)
+        .selected_text(value.as_str())
+        .show_ui(ui, |ui| {
+            for option in options {
+                ui.selectable_value(value, option.to_string(), *option);
             }
-            let frame = s.frames_ahead();
-
-            info!("GGRS FRAME: {:?}", frame);
-        }
-        _ => panic!("This example focuses on p2p."),
-    }
+        });
 }
@@ -0,0 +1,205 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use bevy_egui::egui::plot::{Line, Plot, PlotPoints};
+use bevy_egui::{egui, EguiContexts};
+use bevy_ggrs::ggrs::NetworkStats;
+use bevy_ggrs::Session;
+
+use crate::{network::NetSettings, time_sync::TimeSync, GgrsConfig};
+
+/// How many samples the graphs keep, one per `SAMPLE_INTERVAL`
+const HISTORY_LEN: usize = 60;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Confirmed simulation frame, rolled back with everything else
+#[derive(Resource, Reflect, Default, Debug)]
+pub struct FrameCount(pub u32);
+
+/// Every time the simulation ran, including resimulated frames. Not rolled
+/// back, so the difference to `FrameCount` is how much got resimulated.
+#[derive(Resource, Default, Debug)]
+pub struct SimSteps(pub u32);
+
+pub fn count_frames(mut frame: ResMut<FrameCount>, mut steps: ResMut<SimSteps>) {
+    frame.0 = frame.0.wrapping_add(1);
+    steps.0 = steps.0.wrapping_add(1);
+}
+
+#[derive(Resource, Debug)]
+pub struct NetStatsOverlay {
+    pub visible: bool,
+    last_frame: u32,
+    last_steps: u32,
+    window_start: Instant,
+    rollbacks: u32,
+    resimulated: u32,
+    pub rollbacks_per_second: u32,
+    pub resimulated_per_second: u32,
+    stats: Option<NetworkStats>,
+    ping_history: VecDeque<f64>,
+    rollback_history: VecDeque<f64>,
+    last_sample: Instant,
+}
+
+impl Default for NetStatsOverlay {
+    fn default() -> Self {
+        Self {
+            visible: false,
+            last_frame: 0,
+            last_steps: 0,
+            window_start: Instant::now(),
+            rollbacks: 0,
+            resimulated: 0,
+            rollbacks_per_second: 0,
+            resimulated_per_second: 0,
+            stats: None,
+            ping_history: VecDeque::with_capacity(HISTORY_LEN),
+            rollback_history: VecDeque::with_capacity(HISTORY_LEN),
+            last_sample: Instant::now(),
+        }
+    }
+}
+
+fn push_sample(history: &mut VecDeque<f64>, value: f64) {
+    if history.len() == HISTORY_LEN {
+        history.pop_front();
+    }
+    history.push_back(value);
+}
+
+pub fn update_net_stats(
+    mut overlay: ResMut<NetStatsOverlay>,
+    mut session: ResMut<Session<GgrsConfig>>,
+    frame: Res<FrameCount>,
+    steps: Res<SimSteps>,
+) {
+    // anything run on top of the newly advanced frames was a resimulation
+    let advanced = frame.0.wrapping_sub(overlay.last_frame);
+    let ran = steps.0.wrapping_sub(overlay.last_steps);
+    let resimulated = ran.saturating_sub(advanced);
+    if resimulated > 0 {
+        overlay.rollbacks += 1;
+        overlay.resimulated += resimulated;
+    }
+    overlay.last_frame = frame.0;
+    overlay.last_steps = steps.0;
+
+    if overlay.window_start.elapsed() >= Duration::from_secs(1) {
+        overlay.rollbacks_per_second = overlay.rollbacks;
+        overlay.resimulated_per_second = overlay.resimulated;
+        overlay.rollbacks = 0;
+        overlay.resimulated = 0;
+        overlay.window_start = Instant::now();
+    }
+
+    if let Session::P2PSession(s) = session.as_mut() {
+        overlay.stats = s
+            .remote_player_handles()
+            .into_iter()
+            .find_map(|handle| s.network_stats(handle).ok());
+    }
+
+    if overlay.last_sample.elapsed() >= SAMPLE_INTERVAL {
+        let ping = overlay
+            .stats
+            .as_ref()
+            .map_or(0.0, |stats| stats.ping as f64);
+        let rollbacks = overlay.rollbacks_per_second as f64;
+        push_sample(&mut overlay.ping_history, ping);
+        push_sample(&mut overlay.rollback_history, rollbacks);
+        overlay.last_sample = Instant::now();
+    }
+}
+
+pub fn net_stats_overlay(
+    mut contexts: EguiContexts,
+    keys: Res<Input<KeyCode>>,
+    mut overlay: ResMut<NetStatsOverlay>,
+    settings: Res<NetSettings>,
+    time_sync: Res<TimeSync>,
+) {
+    if keys.just_pressed(KeyCode::F3) {
+        overlay.visible = !overlay.visible;
+    }
+
+    // touch screens have no F3
+    egui::Area::new("net_stats_toggle")
+        .anchor(egui::Align2::RIGHT_TOP, egui::Vec2::new(-8.0, 8.0))
+        .show(contexts.ctx_mut(), |ui| {
+            if ui.small_button("net").clicked() {
+                overlay.visible = !overlay.visible;
+            }
+        });
+
+    if !overlay.visible {
+        return;
+    }
+
+    egui::Window::new("Network")
+        .resizable(false)
+        .collapsible(false)
+        .anchor(egui::Align2::RIGHT_TOP, egui::Vec2::new(-8.0, 36.0))
+        .show(contexts.ctx_mut(), |ui| {
+            match &overlay.stats {
+                Some(stats) => {
+                    ui.label(format!("Ping: {} ms", stats.ping));
+                    ui.label(format!(
+                        "Frames behind: local {} / remote {}",
+                        stats.local_frames_behind, stats.remote_frames_behind
+                    ));
+                    ui.label(format!("Bandwidth: {} kbps", stats.kbps_sent));
+                    // GGRS doesn't report packet loss, so this isn't it. The
+                    // queue only grows when inputs go missing or arrive late.
+                    ui.label(format!("Inputs awaiting ack: {}", stats.send_queue_len))
+                        .on_hover_text("Not packet loss, climbs when packets are lost or slow");
+                }
+                None => {
+                    ui.label("Synchronizing...");
+                }
+            }
+            ui.label(format!(
+                "Rollbacks: {}/s ({} frames/s)",
+                overlay.rollbacks_per_second, overlay.resimulated_per_second
+            ));
+            ui.label(format!("Input delay: {} frames", settings.input_delay));
+            if time_sync.skipping() {
+                ui.label("Time sync: waiting for opponent");
+            } else if time_sync.frames_ahead > 0 {
+                ui.label(format!(
+                    "Time sync: {} frames ahead, running slow",
+                    time_sync.frames_ahead
+                ));
+            } else {
+                ui.label("Time sync: in step");
+            }
+
+            ui.separator();
+            ui.label("Ping (ms)");
+            Plot::new("ping_history")
+                .height(60.0)
+                .allow_drag(false)
+                .allow_zoom(false)
+                .allow_scroll(false)
+                .include_y(0.0)
+                .show(ui, |plot| {
+                    plot.line(Line::new(PlotPoints::from_ys_f64(
+                        overlay.ping_history.make_contiguous(),
+                    )));
+                });
+            ui.label("Rollbacks per second");
+            Plot::new("rollback_history")
+                .height(60.0)
+                .allow_drag(false)
+                .allow_zoom(false)
+                .allow_scroll(false)
+                .include_y(0.0)
+                .show(ui, |plot| {
+                    plot.line(Line::new(PlotPoints::from_ys_f64(
+                        overlay.rollback_history.make_contiguous(),
+                    )));
+                });
+        });
+}
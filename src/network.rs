@@ -0,0 +1,918 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet, Instant};
+use bevy_egui::egui::{self, Pos2};
+use bevy_egui::EguiContexts;
+use bevy_ggrs::ggrs::{self, NonBlockingSocket, PlayerType};
+use bevy_ggrs::Session;
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{serde_json, Event};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chat::{chat_box, send_chat, Chat},
+    components::{BarCamera, Bullet, FallenStats, Nostr, Player},
+    forwarding,
+    game_server::ServerConnection,
+    invite::{invite_panel, JoinForm, JoinGate},
+    lobby::HostedGame,
+    net_stats::{FrameCount, NetStatsOverlay, SimSteps},
+    profiles::{PlayerKeys, ProfileUi},
+    reconnect::{AwaitingReconnect, MatchSnapshot, OpponentLeft, Rejoin},
+    results::MatchOver,
+    socket_builder::{socket_builder, GGRS_CHANNEL, LOBBY_CHANNEL},
+    time_sync::TimeSync,
+    GameState, GgrsConfig, LocalPlayerHandle,
+};
+
+/// Simulation steps per second, matches the `GGRSPlugin` default
+pub const FPS: usize = 60;
+
+/// How long GGRS waits on a silent peer before dropping them
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DISCONNECT_NOTIFY_DELAY: Duration = Duration::from_millis(500);
+/// How long a session through a game server may take to synchronize, it never
+/// does when only one of us reached the server
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the forfeit screen stays up before going back to the menu
+const FORFEIT_RETURN: Duration = Duration::from_secs(5);
+
+const PING_SAMPLES: usize = 8;
+const PING_INTERVAL: Duration = Duration::from_millis(150);
+
+const MIN_INPUT_DELAY: usize = 1;
+const MAX_INPUT_DELAY: usize = 8;
+const MIN_PREDICTION: usize = 8;
+pub const MAX_PREDICTION: usize = 16;
+/// Extra frames of prediction on top of the measured latency to absorb jitter
+const PREDICTION_MARGIN: usize = 4;
+
+/// Whether we listed the game or joined someone else's
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LobbyRole {
+    Host,
+    #[default]
+    Joiner,
+}
+
+/// Input delay and max prediction window the GGRS session is started with
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetSettings {
+    pub input_delay: usize,
+    pub max_prediction: usize,
+    /// The game server the host picked to forward inputs, see `game_server`
+    #[serde(default)]
+    pub server: Option<XOnlyPublicKey>,
+}
+
+impl Default for NetSettings {
+    fn default() -> Self {
+        Self {
+            input_delay: 2,
+            max_prediction: MIN_PREDICTION,
+            server: None,
+        }
+    }
+}
+
+impl NetSettings {
+    /// Hides about half the one way latency behind input delay and leaves the
+    /// rest to rollback.
+    pub fn from_rtt(rtt: Duration) -> Self {
+        let frame = Duration::from_secs(1) / FPS as u32;
+        let one_way_frames = (rtt / 2).as_micros().div_ceil(frame.as_micros()) as usize;
+        let input_delay = one_way_frames
+            .div_ceil(2)
+            .clamp(MIN_INPUT_DELAY, MAX_INPUT_DELAY);
+        let max_prediction = (one_way_frames.saturating_sub(input_delay) + PREDICTION_MARGIN)
+            .clamp(MIN_PREDICTION, MAX_PREDICTION);
+        Self {
+            input_delay,
+            max_prediction,
+            server: None,
+        }
+    }
+}
+
+/// Round trip measurements to the other peer taken while in the lobby
+#[derive(Resource, Default, Debug)]
+pub struct RttProbe {
+    next_id: u32,
+    last_sent: Option<Instant>,
+    in_flight: HashMap<u32, Instant>,
+    samples: Vec<Duration>,
+    /// Set by the host when they pick the settings by hand
+    pub overridden: bool,
+}
+
+impl RttProbe {
+    pub fn done(&self) -> bool {
+        self.samples.len() >= PING_SAMPLES
+    }
+
+    /// Median of the samples so far, a single slow packet doesn't skew it
+    pub fn rtt(&self) -> Option<Duration> {
+        let mut samples = self.samples.clone();
+        samples.sort();
+        samples.get(samples.len() / 2).copied()
+    }
+}
+
+/// Everything sent over the reliable lobby channel
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LobbyMessage {
+    Ping(u32),
+    Pong(u32),
+    /// Sent by the host, both peers start the session with these settings
+    Start(NetSettings),
+    /// Sent to a peer rejoining after a reload, the session restarts from here
+    Resume(Box<MatchSnapshot>),
+    /// The host won't play with us, see `JoinGate`
+    Rejected(String),
+    /// The host let us into the room
+    Accepted,
+    Ready(bool),
+    /// One signature of the match result, see `results`
+    Result(Box<Event>),
+    /// Whether we'd play the same opponent again
+    Rematch(bool),
+}
+
+pub fn send_lobby_message(
+    socket: &mut MatchboxSocket<MultipleChannels>,
+    peer: PeerId,
+    message: &LobbyMessage,
+) {
+    let packet = serde_json::to_vec(message).expect("serializing lobby message");
+    socket
+        .channel(LOBBY_CHANNEL)
+        .send(packet.into_boxed_slice(), peer);
+}
+
+pub fn receive_lobby_messages(
+    socket: &mut MatchboxSocket<MultipleChannels>,
+) -> Vec<(PeerId, LobbyMessage)> {
+    socket
+        .channel(LOBBY_CHANNEL)
+        .receive()
+        .into_iter()
+        .filter_map(|(peer, packet)| match serde_json::from_slice(&packet) {
+            Ok(message) => Some((peer, message)),
+            Err(e) => {
+                warn!("dropping malformed lobby message from {peer:?}: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn open_socket(commands: &mut Commands, nostr: &Nostr) {
+    warn!("connecting to nostr relay: {:?}", nostr.relay);
+    warn!("pubkey: {:?}", nostr.keys.public_key());
+    commands.open_socket(socket_builder(nostr.relay.to_owned(), nostr.keys.clone()));
+}
+
+pub fn start_matchbox_socket(mut commands: Commands, nostr_query: Query<&Nostr>) {
+    let nostr = nostr_query.iter().next().unwrap();
+
+    open_socket(&mut commands, nostr);
+    commands.insert_resource(RttProbe::default());
+    commands.insert_resource(LobbyRoom::default());
+    commands.insert_resource(Chat::default());
+}
+
+/// Whatever sent us back to the menu, the connection goes with it. A match
+/// keeps it, `teardown_match` closes it after.
+pub fn leave_matchmaking(mut commands: Commands, state: Res<State<GameState>>) {
+    // on exit the state already is the one we're going to
+    if state.0 == GameState::InGame {
+        return;
+    }
+    commands.remove_resource::<MatchboxSocket<MultipleChannels>>();
+    commands.remove_resource::<LobbyRoom>();
+    commands.remove_resource::<RttProbe>();
+}
+
+/// The pre-game room. The host picks the opponent out of everyone who got
+/// past the `JoinGate`, then both toggle ready and the match starts once both
+/// are.
+#[derive(Resource, Default, Debug)]
+pub struct LobbyRoom {
+    /// The peer the host let in, for the joiner the host once it accepted us
+    pub accepted: Option<PeerId>,
+    /// Turned away by the host, not offered again
+    kicked: HashSet<PeerId>,
+    pub ready: bool,
+    pub opponent_ready: bool,
+    /// Set by the host to play through a game server
+    pub server: Option<XOnlyPublicKey>,
+    /// Clicked since the last update
+    pub actions: Vec<RoomAction>,
+}
+
+impl LobbyRoom {
+    fn leave(&mut self) {
+        self.accepted = None;
+        self.ready = false;
+        self.opponent_ready = false;
+    }
+}
+
+/// Clicked in `lobby_panel`, carried out by `wait_for_players`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomAction {
+    Accept(PeerId),
+    Kick(PeerId),
+    Ready(bool),
+}
+
+/// Runs the room: lobby messages, the ping, whatever was clicked and the
+/// session once both are ready
+#[allow(clippy::too_many_arguments)]
+pub fn wait_for_players(
+    mut commands: Commands,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    nostr_query: Query<&Nostr>,
+    role: Res<LobbyRole>,
+    mut probe: ResMut<RttProbe>,
+    mut settings: ResMut<NetSettings>,
+    rejoin: Option<Res<Rejoin>>,
+    gate: Option<Res<JoinGate>>,
+    mut join_form: ResMut<JoinForm>,
+    mut room: ResMut<LobbyRoom>,
+) {
+    // regularly call update_peers to update the list of connected peers
+    for (peer, new_state) in socket.update_peers() {
+        // you can also handle the specific dis(connections) as they occur:
+        match new_state {
+            PeerState::Connected => info!("peer {peer:?} connected"),
+            PeerState::Disconnected => {
+                info!("peer {peer:?} disconnected");
+                if room.accepted == Some(peer) {
+                    // whoever comes next gets measured from scratch
+                    *probe = RttProbe::default();
+                    room.leave();
+                }
+            }
+        }
+    }
+
+    let local = PeerId(nostr_query.single().keys.public_key());
+
+    let mut start = None;
+    for (from, message) in receive_lobby_messages(&mut socket) {
+        // whoever the gate doesn't let in isn't listened to
+        if gate.as_ref().map_or(false, |gate| !gate.admits(&from)) {
+            continue;
+        }
+        match message {
+            LobbyMessage::Ping(id) => {
+                send_lobby_message(&mut socket, from, &LobbyMessage::Pong(id));
+            }
+            LobbyMessage::Pong(id) => {
+                if let Some(sent) = probe.in_flight.remove(&id) {
+                    probe.samples.push(Instant::now().duration_since(sent));
+                }
+            }
+            LobbyMessage::Start(host_settings) => {
+                if *role == LobbyRole::Joiner && rejoin.is_none() && room.accepted == Some(from) {
+                    start = Some(host_settings);
+                }
+            }
+            LobbyMessage::Resume(snapshot) => {
+                if rejoin.is_some() {
+                    room.accepted = Some(from);
+                    start = Some(snapshot.settings);
+                    commands.remove_resource::<Rejoin>();
+                    commands.insert_resource(*snapshot);
+                }
+            }
+            LobbyMessage::Rejected(reason) => {
+                if *role == LobbyRole::Joiner {
+                    warn!("host turned us away: {reason}");
+                    join_form.error = Some(reason);
+                    // dropping the socket closes the connection
+                    commands.remove_resource::<MatchboxSocket<MultipleChannels>>();
+                    next_state.set(GameState::Menu);
+                    return;
+                }
+            }
+            LobbyMessage::Accepted => {
+                if *role == LobbyRole::Joiner {
+                    room.accepted = Some(from);
+                }
+            }
+            LobbyMessage::Ready(ready) => {
+                if room.accepted == Some(from) {
+                    room.opponent_ready = ready;
+                }
+            }
+            LobbyMessage::Result(_) | LobbyMessage::Rematch(_) => {}
+        }
+    }
+
+    let ping_due = probe
+        .last_sent
+        .map_or(true, |sent| sent.elapsed() >= PING_INTERVAL);
+    if let (Some(peer), false, true) = (room.accepted, probe.done(), ping_due) {
+        let id = probe.next_id;
+        probe.next_id += 1;
+        probe.last_sent = Some(Instant::now());
+        probe.in_flight.insert(id, Instant::now());
+        send_lobby_message(&mut socket, peer, &LobbyMessage::Ping(id));
+    }
+
+    let rtt = probe.rtt();
+    if let (Some(rtt), false) = (rtt, probe.overridden) {
+        *settings = NetSettings::from_rtt(rtt);
+    }
+
+    for action in std::mem::take(&mut room.actions) {
+        match action {
+            RoomAction::Accept(peer) => {
+                info!("accepted {peer:?}");
+                *probe = RttProbe::default();
+                room.accepted = Some(peer);
+                send_lobby_message(&mut socket, peer, &LobbyMessage::Accepted);
+            }
+            RoomAction::Kick(peer) => {
+                info!("kicked {peer:?}");
+                send_lobby_message(
+                    &mut socket,
+                    peer,
+                    &LobbyMessage::Rejected("Kicked by the host".to_string()),
+                );
+                room.kicked.insert(peer);
+                if room.accepted == Some(peer) {
+                    room.leave();
+                }
+            }
+            RoomAction::Ready(ready) => {
+                room.ready = ready;
+                if let Some(peer) = room.accepted {
+                    send_lobby_message(&mut socket, peer, &LobbyMessage::Ready(ready));
+                }
+            }
+        }
+    }
+
+    // the host calls it once everyone is ready, with the settings measured so far
+    let measured = probe.done() || probe.overridden;
+    if let (LobbyRole::Host, Some(peer), true) = (*role, room.accepted, measured) {
+        if room.ready && room.opponent_ready && start.is_none() {
+            settings.server = room.server;
+            send_lobby_message(&mut socket, peer, &LobbyMessage::Start(*settings));
+            start = Some(*settings);
+        }
+    }
+
+    let (Some(session_settings), Some(peer)) = (start, room.accepted) else {
+        return;
+    };
+    *settings = session_settings;
+
+    info!("All peers have joined, going in-game");
+    start_session(&mut commands, &mut socket, local, peer, session_settings);
+    next_state.set(GameState::InGame);
+}
+
+/// What the room looks like, clicks go to `wait_for_players`
+#[allow(clippy::too_many_arguments)]
+pub fn lobby_panel(
+    mut contexts: EguiContexts,
+    window: Query<&Window>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    nostr_query: Query<&Nostr>,
+    role: Res<LobbyRole>,
+    mut probe: ResMut<RttProbe>,
+    mut settings: ResMut<NetSettings>,
+    rejoin: Option<Res<Rejoin>>,
+    mut profile_ui: ProfileUi,
+    gate: Option<Res<JoinGate>>,
+    hosted: Option<Res<HostedGame>>,
+    mut room: ResMut<LobbyRoom>,
+    mut chat: ResMut<Chat>,
+) {
+    let window = window.iter().next().unwrap();
+    let screen_size = egui::Vec2::new(window.width(), window.height());
+    let screen_center = screen_size / 2.0;
+    let pos = Pos2::new(screen_center.x, screen_center.y / 2.0);
+    let local = PeerId(nostr_query.single().keys.public_key());
+    let rtt = probe.rtt();
+
+    // everyone who got past the gate and wasn't kicked, the host picks from these
+    let requests: Vec<PeerId> = socket
+        .connected_peers()
+        .filter(|peer| gate.as_ref().map_or(true, |gate| gate.admits(peer)))
+        .filter(|peer| !room.kicked.contains(peer))
+        .collect();
+
+    let mut action = None;
+    let mut message = None;
+    egui::Window::new("web21")
+        .resizable(false)
+        .collapsible(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .fixed_pos(pos)
+        .show(contexts.ctx_mut(), |ui| {
+            if rejoin.is_some() {
+                ui.heading("Rejoining the match...");
+                return;
+            }
+
+            ui.heading("Lobby");
+            let Some(opponent) = room.accepted else {
+                if *role == LobbyRole::Joiner {
+                    ui.label("Waiting for the host to let you in...");
+                    return;
+                }
+                if requests.is_empty() {
+                    ui.label("Waiting for players...");
+                } else {
+                    ui.label("Join requests:");
+                }
+                for peer in &requests {
+                    ui.horizontal(|ui| {
+                        profile_ui.label(ui, peer.0);
+                        if ui.button("Accept").clicked() {
+                            action = Some(RoomAction::Accept(*peer));
+                        }
+                        if ui.button("Kick").clicked() {
+                            action = Some(RoomAction::Kick(*peer));
+                        }
+                    });
+                }
+                if let Some(hosted) = &hosted {
+                    ui.separator();
+                    invite_panel(ui, &hosted.invite(nostr_query.single()));
+                }
+                return;
+            };
+
+            ui.horizontal(|ui| {
+                profile_ui.label(ui, local.0);
+                ui.weak("(you)");
+                let mut ready = room.ready;
+                if ui.checkbox(&mut ready, "Ready").changed() {
+                    action = Some(RoomAction::Ready(ready));
+                }
+            });
+            ui.horizontal(|ui| {
+                profile_ui.label(ui, opponent.0);
+                if room.opponent_ready {
+                    ui.colored_label(egui::Color32::LIGHT_GREEN, "ready");
+                } else {
+                    ui.weak("not ready");
+                }
+                if *role == LobbyRole::Host && ui.small_button("Kick").clicked() {
+                    action = Some(RoomAction::Kick(opponent));
+                }
+            });
+            ui.separator();
+            message = chat_box(ui, &mut chat, &mut profile_ui.profiles, local);
+            ui.separator();
+
+            match rtt {
+                Some(rtt) => ui.label(format!("Ping: {} ms", rtt.as_millis())),
+                None => ui.label("Measuring ping..."),
+            };
+
+            if *role == LobbyRole::Joiner {
+                ui.label(format!("Input delay: {} frames", settings.input_delay));
+                ui.label(format!(
+                    "Prediction window: {} frames",
+                    settings.max_prediction
+                ));
+                ui.label("The match starts once both of you are ready");
+                return;
+            }
+
+            ui.checkbox(&mut probe.overridden, "Override network settings");
+            ui.add_enabled_ui(probe.overridden, |ui| {
+                ui.add(
+                    egui::Slider::new(&mut settings.input_delay, MIN_INPUT_DELAY..=MAX_INPUT_DELAY)
+                        .text("Input delay (frames)"),
+                );
+                ui.add(
+                    egui::Slider::new(
+                        &mut settings.max_prediction,
+                        MIN_PREDICTION..=MAX_PREDICTION,
+                    )
+                    .text("Prediction window (frames)"),
+                );
+            });
+            if room.ready && room.opponent_ready && !(probe.done() || probe.overridden) {
+                ui.label("Measuring ping before starting...");
+            }
+        });
+
+    if let Some(action) = action {
+        room.actions.push(action);
+    }
+    if let (Some(message), Some(peer)) = (message, room.accepted) {
+        send_chat(&mut socket, &mut chat, local, peer, message);
+    }
+}
+
+/// Both players in the same order on both sides, so player handles agree
+pub fn players(local: PeerId, opponent: PeerId) -> Vec<PlayerType<PeerId>> {
+    let mut ids = vec![local, opponent];
+    ids.sort();
+    ids.into_iter()
+        .map(|id| {
+            if id == local {
+                PlayerType::Local
+            } else {
+                PlayerType::Remote(id)
+            }
+        })
+        .collect()
+}
+
+/// The socket's GGRS channel. GGRS owns whatever it's handed, so it gets a
+/// shared handle and the channel outlives the session for a rematch.
+#[derive(Resource, Clone)]
+pub struct GgrsChannel {
+    channel: Arc<Mutex<WebRtcChannel>>,
+    /// Everything goes through this game server instead of straight to the
+    /// opponent, see `forwarding`
+    pub server: Option<PeerId>,
+}
+
+impl GgrsChannel {
+    /// Throws away whatever is still in flight from a previous session
+    pub fn drain(&self) {
+        let stale = self.channel.lock().unwrap().receive().len();
+        if stale > 0 {
+            info!("dropped {stale} packets of the last session");
+        }
+    }
+}
+
+impl NonBlockingSocket<PeerId> for GgrsChannel {
+    fn send_to(&mut self, msg: &ggrs::Message, addr: &PeerId) {
+        let mut channel = self.channel.lock().unwrap();
+        let Some(server) = self.server else {
+            channel.send_to(msg, addr);
+            return;
+        };
+        let payload = bincode::serialize(msg).expect("serializing GGRS message");
+        channel.send(forwarding::wrap(addr.0, &payload), server);
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(PeerId, ggrs::Message)> {
+        let mut channel = self.channel.lock().unwrap();
+        let Some(server) = self.server else {
+            return channel.receive_all_messages();
+        };
+        channel
+            .receive()
+            .into_iter()
+            .filter(|(from, _)| *from == server)
+            .filter_map(|(_, packet)| {
+                let (sender, payload) = forwarding::unwrap(&packet)?;
+                let msg = bincode::deserialize(payload).ok()?;
+                Some((PeerId(sender), msg))
+            })
+            .collect()
+    }
+}
+
+pub fn start_session(
+    commands: &mut Commands,
+    socket: &mut MatchboxSocket<MultipleChannels>,
+    local: PeerId,
+    opponent: PeerId,
+    settings: NetSettings,
+) {
+    // move the channel out of the socket (required because GGRS takes ownership of it)
+    let channel = GgrsChannel {
+        channel: Arc::new(Mutex::new(socket.take_channel(GGRS_CHANNEL).unwrap())),
+        server: settings.server.map(PeerId),
+    };
+    commands.insert_resource(channel.clone());
+    match channel.server {
+        // the session starts once the server is connected
+        Some(server) => commands.insert_resource(ServerConnection::new(server, opponent, settings)),
+        None => start_session_on(commands, channel, local, opponent, settings),
+    }
+}
+
+/// Starts a GGRS session over a channel an earlier session already used
+pub fn start_session_on(
+    commands: &mut Commands,
+    channel: GgrsChannel,
+    local: PeerId,
+    opponent: PeerId,
+    settings: NetSettings,
+) {
+    let players = players(local, opponent);
+    let keys = players
+        .iter()
+        .map(|player| match player {
+            PlayerType::Remote(peer) => peer.0,
+            _ => local.0,
+        })
+        .collect();
+    commands.insert_resource(PlayerKeys(keys));
+
+    // create a GGRS P2P session
+    let mut session_builder = ggrs::SessionBuilder::<GgrsConfig>::new()
+        .with_num_players(2)
+        .with_input_delay(settings.input_delay)
+        .with_max_prediction_window(settings.max_prediction)
+        .with_disconnect_timeout(DISCONNECT_TIMEOUT)
+        .with_disconnect_notify_delay(DISCONNECT_NOTIFY_DELAY);
+
+    for (i, player) in players.into_iter().enumerate() {
+        if player == PlayerType::Local {
+            info!("adding local player: {:?}", i);
+            commands.insert_resource(LocalPlayerHandle(i));
+        }
+
+        session_builder = session_builder
+            .add_player(player, i)
+            .expect("failed to add player");
+    }
+    info!("ggrs session started: {:?}", session_builder);
+
+    // start the GGRS session
+    let ggrs_session = session_builder
+        .start_p2p_session(channel)
+        .expect("failed to start session");
+
+    commands.insert_resource(Session::P2PSession(ggrs_session));
+}
+
+/// Set while the opponent's connection is interrupted, GGRS drops them at `deadline`
+#[derive(Resource, Debug)]
+pub struct Interruption {
+    pub peer: PeerId,
+    pub deadline: Instant,
+}
+
+/// Someone left the match and lost it by forfeit, usually the opponent
+#[derive(Resource, Debug)]
+pub struct Forfeit {
+    pub return_at: Instant,
+    /// It was us, we lost our link to the game server
+    pub ours: bool,
+}
+
+pub fn forfeit(commands: &mut Commands, peer: PeerId) {
+    warn!("peer {peer:?} left the match");
+    commands.remove_resource::<Interruption>();
+    commands.insert_resource(Forfeit {
+        return_at: Instant::now() + FORFEIT_RETURN,
+        ours: false,
+    });
+}
+
+/// Our own link to the game server went, the opponent's side gets the win
+fn concede(commands: &mut Commands) {
+    warn!("lost the game server, the match goes to the opponent");
+    commands.remove_resource::<Session<GgrsConfig>>();
+    commands.remove_resource::<Interruption>();
+    commands.insert_resource(Forfeit {
+        return_at: Instant::now() + FORFEIT_RETURN,
+        ours: true,
+    });
+}
+
+/// Neither of us wins a match that never got going
+fn no_contest(join_form: &mut JoinForm, next_state: &mut NextState<GameState>, reason: &str) {
+    warn!("no contest: {reason}");
+    join_form.error = Some(format!("{reason}, no contest"));
+    next_state.set(GameState::Menu);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_ggrs_events(
+    mut commands: Commands,
+    mut session: ResMut<Session<GgrsConfig>>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut opponent_left: EventWriter<OpponentLeft>,
+    mut time_sync: ResMut<TimeSync>,
+    channel: Option<Res<GgrsChannel>>,
+    over: Option<Res<MatchOver>>,
+    mut join_form: ResMut<JoinForm>,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut synchronizing_since: Local<Option<Instant>>,
+) {
+    let Session::P2PSession(s) = session.as_mut() else {
+        panic!("This example focuses on p2p.");
+    };
+    // once the match is over the server isn't needed anymore
+    let server = channel
+        .and_then(|channel| channel.server)
+        .filter(|_| over.is_none());
+
+    // the WebRTC connection closing is usually noticed before GGRS times out
+    let mut left = Vec::new();
+    for (peer, new_state) in socket.update_peers() {
+        if matches!(new_state, PeerState::Disconnected) {
+            left.push(peer);
+        }
+    }
+    // only our own link to the server tells us who dropped it
+    if server.map_or(false, |server| left.contains(&server)) {
+        *synchronizing_since = None;
+        concede(&mut commands);
+        return;
+    }
+
+    if s.current_state() == ggrs::SessionState::Running {
+        *synchronizing_since = None;
+    } else if server.is_some()
+        && synchronizing_since
+            .get_or_insert_with(Instant::now)
+            .elapsed()
+            >= SYNC_TIMEOUT
+    {
+        *synchronizing_since = None;
+        no_contest(
+            &mut join_form,
+            &mut next_state,
+            "Couldn't start the match through the game server",
+        );
+        return;
+    }
+
+    let mut events = Vec::new();
+    for event in s.events() {
+        info!("GGRS Event: {:?}", event);
+        match event {
+            ggrs::GGRSEvent::NetworkInterrupted {
+                addr,
+                disconnect_timeout,
+            } => {
+                commands.insert_resource(Interruption {
+                    peer: addr,
+                    deadline: Instant::now() + Duration::from_millis(disconnect_timeout as u64),
+                });
+            }
+            ggrs::GGRSEvent::NetworkResumed { .. } => {
+                commands.remove_resource::<Interruption>();
+            }
+            ggrs::GGRSEvent::Disconnected { addr } => events.push(addr),
+            ggrs::GGRSEvent::WaitRecommendation { skip_frames } => {
+                time_sync.skip(skip_frames);
+            }
+            _ => {}
+        }
+    }
+    // through a server GGRS only ever hears from the server. Ours is still
+    // there, so with the opponent still connected to us it's their link to
+    // the server that went quiet. They forfeit, no waiting for a rejoin.
+    let opponent_here = |peer: &&PeerId| {
+        socket
+            .connected_peers()
+            .any(|connected| connected == **peer)
+    };
+    if let (Some(_), Some(peer)) = (server, events.iter().find(opponent_here)) {
+        *synchronizing_since = None;
+        forfeit(&mut commands, *peer);
+        commands.remove_resource::<Session<GgrsConfig>>();
+        return;
+    }
+
+    for peer in left.into_iter().chain(events) {
+        opponent_left.send(OpponentLeft { peer });
+    }
+}
+
+pub fn interruption_banner(
+    mut contexts: EguiContexts,
+    interruption: Option<Res<Interruption>>,
+    awaiting: Option<Res<AwaitingReconnect>>,
+    forfeited: Option<Res<Forfeit>>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if let Some(forfeited) = forfeited {
+        let remaining = forfeited
+            .return_at
+            .saturating_duration_since(Instant::now());
+        let title = if forfeited.ours {
+            "Game server lost"
+        } else {
+            "Opponent left"
+        };
+        egui::Window::new(title)
+            .resizable(false)
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_TOP, egui::Vec2::new(0.0, 16.0))
+            .show(contexts.ctx_mut(), |ui| {
+                if forfeited.ours {
+                    ui.heading("You lost the connection to the game server");
+                    ui.label("The match goes to your opponent.");
+                } else {
+                    ui.heading("Your opponent left the match, you win!");
+                }
+                ui.label(format!(
+                    "Returning to the menu in {}s",
+                    remaining.as_secs() + 1
+                ));
+                if ui.button("Back to menu").clicked() || remaining.is_zero() {
+                    next_state.set(GameState::Menu);
+                }
+            });
+    } else if let Some(awaiting) = awaiting {
+        let remaining = awaiting.deadline.saturating_duration_since(Instant::now());
+        egui::Window::new("Opponent disconnected")
+            .resizable(false)
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_TOP, egui::Vec2::new(0.0, 16.0))
+            .show(contexts.ctx_mut(), |ui| {
+                ui.heading("Opponent disconnected");
+                ui.label(format!(
+                    "Match paused, waiting {}s for them to come back...",
+                    remaining.as_secs() + 1
+                ));
+            });
+    } else if let Some(interruption) = interruption {
+        let remaining = interruption
+            .deadline
+            .saturating_duration_since(Instant::now());
+        egui::Window::new("Connection interrupted")
+            .resizable(false)
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_TOP, egui::Vec2::new(0.0, 16.0))
+            .show(contexts.ctx_mut(), |ui| {
+                ui.heading("Opponent connection interrupted");
+                ui.label(format!(
+                    "Waiting {}s before they forfeit...",
+                    remaining.as_secs() + 1
+                ));
+            });
+    }
+}
+
+/// Drops everything a match left behind, both when it ends normally and when
+/// the opponent disappeared, so the next one starts from a clean slate.
+pub fn teardown_match(
+    mut commands: Commands,
+    entities: Query<Entity, Or<(With<Player>, With<Bullet>, With<BarCamera>)>>,
+) {
+    for entity in entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<Session<GgrsConfig>>();
+    commands.remove_resource::<GgrsChannel>();
+    commands.remove_resource::<ServerConnection>();
+    // dropping the socket closes the WebRTC connections
+    commands.remove_resource::<MatchboxSocket<MultipleChannels>>();
+    commands.remove_resource::<LocalPlayerHandle>();
+    commands.remove_resource::<PlayerKeys>();
+    commands.remove_resource::<RttProbe>();
+    commands.remove_resource::<LobbyRoom>();
+    commands.remove_resource::<Interruption>();
+    commands.remove_resource::<Forfeit>();
+    commands.insert_resource(FrameCount::default());
+    commands.insert_resource(FallenStats::default());
+    commands.insert_resource(SimSteps::default());
+    commands.insert_resource(NetStatsOverlay::default());
+    commands.insert_resource(Chat::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_from_rtt() {
+        assert_eq!(
+            NetSettings::from_rtt(Duration::ZERO),
+            NetSettings {
+                input_delay: MIN_INPUT_DELAY,
+                max_prediction: MIN_PREDICTION,
+                server: None,
+            }
+        );
+        // 133ms round trip is 4 frames each way, half of it goes to input delay
+        let settings = NetSettings::from_rtt(Duration::from_millis(133));
+        assert_eq!(settings.input_delay, 2);
+        // very slow connections are capped
+        let settings = NetSettings::from_rtt(Duration::from_millis(1000));
+        assert_eq!(settings.input_delay, MAX_INPUT_DELAY);
+        assert!(settings.max_prediction <= MAX_PREDICTION);
+    }
+
+    #[test]
+    fn leaving_the_lobby_closes_it() {
+        for (next, kept) in [(GameState::Menu, false), (GameState::InGame, true)] {
+            let mut world = World::new();
+            world.insert_resource(State(next));
+            world.insert_resource(LobbyRoom::default());
+            world.insert_resource(RttProbe::default());
+            let mut schedule = Schedule::new();
+            schedule.add_system(leave_matchmaking);
+            schedule.run(&mut world);
+            assert_eq!(world.contains_resource::<LobbyRoom>(), kept);
+            assert_eq!(world.contains_resource::<RttProbe>(), kept);
+        }
+    }
+}
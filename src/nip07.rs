@@ -0,0 +1,79 @@
+//! Signing with a NIP-07 browser extension (nos2x, Alby, ...) through
+//! `window.nostr`, so players can use the identity they already have.
+//!
+//! The extension never hands out the secret key, so matchbox-nostr keeps
+//! signalling with the local key and only public events are signed here.
+
+use std::str::FromStr;
+
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{serde_json, Event, UnsignedEvent};
+
+#[cfg(target_arch = "wasm32")]
+mod js {
+    use js_sys::{Function, Promise, Reflect, JSON};
+    use wasm_bindgen::{JsCast, JsValue};
+    use wasm_bindgen_futures::JsFuture;
+
+    fn nostr() -> Option<JsValue> {
+        let window = web_sys::window()?;
+        let nostr = Reflect::get(&window, &"nostr".into()).ok()?;
+        (!nostr.is_undefined()).then_some(nostr)
+    }
+
+    pub fn available() -> bool {
+        nostr().is_some()
+    }
+
+    pub async fn call(method: &str, arg: Option<&str>) -> Result<String, String> {
+        let nostr = nostr().ok_or("no NIP-07 extension found")?;
+        let function: Function = Reflect::get(&nostr, &method.into())
+            .and_then(|f| f.dyn_into())
+            .map_err(|_| format!("extension has no {method}"))?;
+        let result = match arg {
+            Some(json) => {
+                let arg = JSON::parse(json).map_err(|e| format!("{e:?}"))?;
+                function.call1(&nostr, &arg)
+            }
+            None => function.call0(&nostr),
+        }
+        .map_err(|e| format!("{e:?}"))?;
+        let result = JsFuture::from(Promise::resolve(&result))
+            .await
+            .map_err(|e| format!("extension refused: {e:?}"))?;
+        match result.as_string() {
+            Some(string) => Ok(string),
+            None => JSON::stringify(&result)
+                .map(String::from)
+                .map_err(|e| format!("{e:?}")),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod js {
+    pub fn available() -> bool {
+        false
+    }
+
+    pub async fn call(_method: &str, _arg: Option<&str>) -> Result<String, String> {
+        Err("NIP-07 is only available in the browser".to_string())
+    }
+}
+
+pub fn available() -> bool {
+    js::available()
+}
+
+pub async fn get_public_key() -> Result<XOnlyPublicKey, String> {
+    let hex = js::call("getPublicKey", None).await?;
+    XOnlyPublicKey::from_str(&hex).map_err(|e| e.to_string())
+}
+
+pub async fn sign_event(unsigned: UnsignedEvent) -> Result<Event, String> {
+    let unsigned = serde_json::to_string(&unsigned).map_err(|e| e.to_string())?;
+    let signed = js::call("signEvent", Some(&unsigned)).await?;
+    let event: Event = serde_json::from_str(&signed).map_err(|e| e.to_string())?;
+    event.verify().map_err(|e| e.to_string())?;
+    Ok(event)
+}
@@ -0,0 +1,424 @@
+//! Kind-0 metadata of the people in the lobby and in the match, so they show
+//! up with a name and a face instead of an npub.
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::render::texture::{CompressedImageFormats, ImageType};
+use bevy_egui::{egui, EguiContexts};
+use nostr_sdk::prelude::{FromBech32, ToBech32};
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{serde_json, Filter, Kind, Metadata, Timestamp};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::{BarCamera, Nostr, Player},
+    relays, storage,
+    tasks::spawn,
+    LocalPlayerHandle,
+};
+
+/// Profiles are refetched after this, the stored copy is used until then
+const PROFILE_TTL: Duration = Duration::from_secs(60 * 60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bigger avatars are skipped rather than decoded
+const MAX_AVATAR_BYTES: usize = 1024 * 1024;
+/// Bigger ones aren't decoded, a small file can still unpack into a huge image
+const MAX_AVATAR_PIXELS: u32 = 1024;
+pub const AVATAR_SIZE: f32 = 20.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Profile {
+    pub name: Option<String>,
+    pub nip05: Option<String>,
+    /// The NIP-05 domain lists this key under the name
+    pub nip05_verified: bool,
+    pub picture: Option<String>,
+    /// unix seconds
+    pub fetched_at: u64,
+    #[serde(skip)]
+    pub avatar: Option<Avatar>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Avatar {
+    pub bytes: Vec<u8>,
+    pub mime: String,
+}
+
+impl Profile {
+    fn from_metadata(metadata: Metadata) -> Self {
+        let name = metadata
+            .display_name
+            .filter(|name| !name.trim().is_empty())
+            .or(metadata.name.filter(|name| !name.trim().is_empty()));
+        Self {
+            name,
+            nip05: metadata.nip05,
+            nip05_verified: false,
+            picture: metadata.picture,
+            fetched_at: Timestamp::now().as_u64(),
+            avatar: None,
+        }
+    }
+
+    fn fresh(&self) -> bool {
+        Timestamp::now().as_u64().saturating_sub(self.fetched_at) < PROFILE_TTL.as_secs()
+    }
+
+    fn storage_key(public_key: &XOnlyPublicKey) -> String {
+        format!("fightgame.profile.{public_key}")
+    }
+
+    fn load(public_key: &XOnlyPublicKey) -> Option<Self> {
+        let profile: Self =
+            serde_json::from_str(&storage::load(&Self::storage_key(public_key))?).ok()?;
+        profile.fresh().then_some(profile)
+    }
+
+    fn save(&self, public_key: &XOnlyPublicKey) {
+        let record = serde_json::to_string(self).expect("serializing profile");
+        storage::save(&Self::storage_key(public_key), &record);
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ProfileState {
+    Loading,
+    /// Nothing published, or the relay didn't answer
+    Missing,
+    Loaded(Profile),
+}
+
+/// Every profile we've looked up this session. Unknown keys asked for through
+/// `get` are fetched in one batch by `fetch_profiles`.
+#[derive(Resource, Default)]
+pub struct Profiles {
+    entries: Arc<Mutex<HashMap<XOnlyPublicKey, ProfileState>>>,
+    pending: HashSet<XOnlyPublicKey>,
+}
+
+impl Profiles {
+    pub fn get(&mut self, public_key: XOnlyPublicKey) -> Option<Profile> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&public_key) {
+            Some(ProfileState::Loaded(profile)) => Some(profile.clone()),
+            Some(_) => None,
+            None => match Profile::load(&public_key) {
+                Some(profile) => {
+                    // the avatar isn't stored, fetch it again
+                    if let Some(picture) = profile.picture.clone() {
+                        spawn(fetch_avatar(self.entries.clone(), public_key, picture));
+                    }
+                    entries.insert(public_key, ProfileState::Loaded(profile.clone()));
+                    Some(profile)
+                }
+                None => {
+                    entries.insert(public_key, ProfileState::Loading);
+                    self.pending.insert(public_key);
+                    None
+                }
+            },
+        }
+    }
+
+    /// Display name, or a shortened npub while there is none
+    pub fn name(&mut self, public_key: XOnlyPublicKey) -> String {
+        self.get(public_key)
+            .and_then(|profile| profile.name)
+            .unwrap_or_else(|| short_npub(&public_key))
+    }
+
+    /// Same as `name`, for the bech32 keys kept in game listings
+    pub fn name_of_npub(&mut self, npub: &str) -> String {
+        match XOnlyPublicKey::from_bech32(npub) {
+            Ok(public_key) => self.name(public_key),
+            Err(_) => npub.to_string(),
+        }
+    }
+
+    fn avatars(&self) -> Vec<(XOnlyPublicKey, Avatar)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(public_key, state)| match state {
+                ProfileState::Loaded(Profile {
+                    avatar: Some(avatar),
+                    ..
+                }) => Some((*public_key, avatar.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub fn short_npub(public_key: &XOnlyPublicKey) -> String {
+    let npub = public_key.to_bech32().unwrap();
+    format!("{}…{}", &npub[..10], &npub[npub.len() - 4..])
+}
+
+pub fn fetch_profiles(mut profiles: ResMut<Profiles>, nostr_query: Query<&Nostr>) {
+    if profiles.pending.is_empty() {
+        return;
+    }
+    let Ok(nostr) = nostr_query.get_single() else {
+        return;
+    };
+    let authors: Vec<XOnlyPublicKey> = profiles.pending.drain().collect();
+    let entries = profiles.entries.clone();
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+
+    spawn(async move {
+        let client = relays::connect(&keys, &relays).await;
+
+        let filter = Filter::new().kind(Kind::Metadata).authors(authors.clone());
+        let events = client
+            .get_events_of(vec![filter], Some(FETCH_TIMEOUT))
+            .await
+            .unwrap_or_default();
+        client.disconnect().await.ok();
+
+        // relays may send several versions, the newest one counts
+        let mut newest = HashMap::new();
+        for event in events {
+            let newer = newest
+                .get(&event.pubkey)
+                .map_or(true, |kept: &nostr_sdk::Event| {
+                    kept.created_at < event.created_at
+                });
+            if newer {
+                newest.insert(event.pubkey, event);
+            }
+        }
+
+        for public_key in authors {
+            let profile = newest
+                .remove(&public_key)
+                .and_then(|event| serde_json::from_str::<Metadata>(&event.content).ok())
+                .map(Profile::from_metadata);
+            let Some(mut profile) = profile else {
+                entries
+                    .lock()
+                    .unwrap()
+                    .insert(public_key, ProfileState::Missing);
+                continue;
+            };
+
+            if let Some(nip05) = &profile.nip05 {
+                profile.nip05_verified = verify_nip05(&public_key, nip05).await;
+            }
+            profile.save(&public_key);
+            let picture = profile.picture.clone();
+            entries
+                .lock()
+                .unwrap()
+                .insert(public_key, ProfileState::Loaded(profile));
+            if let Some(picture) = picture {
+                spawn(fetch_avatar(entries.clone(), public_key, picture));
+            }
+        }
+    });
+}
+
+/// Checks `name@domain` against the domain's `.well-known/nostr.json`
+async fn verify_nip05(public_key: &XOnlyPublicKey, nip05: &str) -> bool {
+    let Some((name, domain)) = nip05.split_once('@') else {
+        return false;
+    };
+    let Ok(url) = reqwest::Url::parse_with_params(
+        &format!("https://{domain}/.well-known/nostr.json"),
+        [("name", name)],
+    ) else {
+        return false;
+    };
+    let Ok(response) = reqwest::get(url).await else {
+        return false;
+    };
+    let Ok(body) = response.text().await else {
+        return false;
+    };
+    let Ok(json) = serde_json::from_str::<serde_json::Value>(&body) else {
+        return false;
+    };
+    json["names"][name]
+        .as_str()
+        .and_then(|hex| XOnlyPublicKey::from_str(hex).ok())
+        .map_or(false, |listed| listed == *public_key)
+}
+
+/// In the browser most image hosts don't allow this (CORS), those players
+/// just go without an avatar
+async fn fetch_avatar(
+    entries: Arc<Mutex<HashMap<XOnlyPublicKey, ProfileState>>>,
+    public_key: XOnlyPublicKey,
+    url: String,
+) {
+    let Ok(response) = reqwest::get(&url).await else {
+        return;
+    };
+    let mime = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|mime| mime.to_str().ok())
+        .unwrap_or("image/png")
+        .to_string();
+    let Ok(bytes) = response.bytes().await else {
+        return;
+    };
+    if bytes.len() > MAX_AVATAR_BYTES {
+        return;
+    }
+    // only reads the header
+    let dimensions = image::io::Reader::new(std::io::Cursor::new(&bytes[..]))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    let Some((width, height)) = dimensions else {
+        return;
+    };
+    if width > MAX_AVATAR_PIXELS || height > MAX_AVATAR_PIXELS {
+        info!("avatar of {public_key} is {width}x{height}, not loading it");
+        return;
+    }
+    if let Some(ProfileState::Loaded(profile)) = entries.lock().unwrap().get_mut(&public_key) {
+        profile.avatar = Some(Avatar {
+            bytes: bytes.to_vec(),
+            mime,
+        });
+    }
+}
+
+/// Decoded avatars, registered with egui
+#[derive(Resource, Default)]
+pub struct AvatarTextures {
+    textures: HashMap<XOnlyPublicKey, (Handle<Image>, egui::TextureId)>,
+    /// Couldn't be decoded, not tried again
+    failed: HashSet<XOnlyPublicKey>,
+}
+
+impl AvatarTextures {
+    pub fn get(&self, public_key: &XOnlyPublicKey) -> Option<egui::TextureId> {
+        self.textures.get(public_key).map(|(_, texture)| *texture)
+    }
+}
+
+pub fn load_avatars(
+    profiles: Res<Profiles>,
+    mut textures: ResMut<AvatarTextures>,
+    mut images: ResMut<Assets<Image>>,
+    mut contexts: EguiContexts,
+) {
+    for (public_key, avatar) in profiles.avatars() {
+        if textures.textures.contains_key(&public_key) || textures.failed.contains(&public_key) {
+            continue;
+        }
+        match Image::from_buffer(
+            &avatar.bytes,
+            ImageType::MimeType(&avatar.mime),
+            CompressedImageFormats::NONE,
+            true,
+        ) {
+            Ok(image) => {
+                let handle = images.add(image);
+                let texture = contexts.add_image(handle.clone());
+                textures.textures.insert(public_key, (handle, texture));
+            }
+            Err(e) => {
+                info!("couldn't decode avatar of {public_key}: {e}");
+                textures.failed.insert(public_key);
+            }
+        }
+    }
+}
+
+/// The two resources `profile_label` needs, for systems that are already
+/// taking a lot of parameters
+#[derive(SystemParam)]
+pub struct ProfileUi<'w> {
+    pub profiles: ResMut<'w, Profiles>,
+    pub avatars: Res<'w, AvatarTextures>,
+}
+
+impl ProfileUi<'_> {
+    pub fn label(&mut self, ui: &mut egui::Ui, public_key: XOnlyPublicKey) {
+        profile_label(ui, &mut self.profiles, &self.avatars, public_key);
+    }
+}
+
+/// Avatar, name and a check mark for a verified NIP-05
+pub fn profile_label(
+    ui: &mut egui::Ui,
+    profiles: &mut Profiles,
+    textures: &AvatarTextures,
+    public_key: XOnlyPublicKey,
+) {
+    if let Some(texture) = textures.get(&public_key) {
+        ui.image(texture, [AVATAR_SIZE, AVATAR_SIZE]);
+    }
+    let profile = profiles.get(public_key);
+    ui.label(profiles.name(public_key));
+    if let Some(Profile {
+        nip05: Some(nip05),
+        nip05_verified: true,
+        ..
+    }) = profile
+    {
+        ui.colored_label(egui::Color32::LIGHT_GREEN, "✔")
+            .on_hover_text(nip05);
+    }
+}
+
+/// Keys of the players by GGRS handle, for the name tags
+#[derive(Resource, Debug, Clone)]
+pub struct PlayerKeys(pub Vec<XOnlyPublicKey>);
+
+pub fn player_name_tags(
+    mut contexts: EguiContexts,
+    mut profiles: ResMut<Profiles>,
+    textures: Res<AvatarTextures>,
+    keys: Res<PlayerKeys>,
+    local: Option<Res<LocalPlayerHandle>>,
+    players: Query<(&Player, &GlobalTransform)>,
+    camera: Query<(&Camera, &GlobalTransform), With<BarCamera>>,
+    window: Query<&Window>,
+) {
+    let (Ok((camera, camera_transform)), Ok(window)) = (camera.get_single(), window.get_single())
+    else {
+        return;
+    };
+
+    for (player, transform) in players.iter() {
+        let Some(public_key) = keys.0.get(player.handle) else {
+            continue;
+        };
+        // a bit above the sprite, the health bar sits below
+        let above = transform.translation() + Vec3::new(0.0, 0.9, 0.0);
+        let Some(viewport) = camera.world_to_viewport(camera_transform, above) else {
+            continue;
+        };
+        // viewport coordinates start at the bottom, egui's at the top
+        let pos = egui::Pos2::new(viewport.x, window.height() - viewport.y);
+        let you = local
+            .as_ref()
+            .map_or(false, |local| local.0 == player.handle);
+
+        egui::Area::new(format!("name_tag_{}", player.handle))
+            .fixed_pos(pos)
+            .pivot(egui::Align2::CENTER_BOTTOM)
+            .interactable(false)
+            .show(contexts.ctx_mut(), |ui| {
+                ui.horizontal(|ui| {
+                    profile_label(ui, &mut profiles, &textures, *public_key);
+                    if you {
+                        ui.weak("(you)");
+                    }
+                });
+            });
+    }
+}
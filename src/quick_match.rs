@@ -0,0 +1,507 @@
+//! Quick match. Everyone looking for a game publishes a short lived "seeking"
+//! event and watches for the others. All seekers sort the same list the same
+//! way and pair up neighbours, so both sides of a pair pick each other without
+//! talking first. The lower key hosts, the other one asks it for a connection.
+//!
+//! Whoever pairs first says so in its event. Seekers that started later and
+//! haven't paired yet go with whoever picked them, everyone else leaves both
+//! of them alone.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::{HashMap, Instant};
+use bevy_egui::{egui, EguiContexts};
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{
+    serde_json, Client, ClientMessage, Event, EventBuilder, Filter, Kind, RelayPoolNotification,
+    Tag, TagKind, Timestamp,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::Nostr,
+    invite::JoinForm,
+    lobby::{GAME_VERSION, MODES},
+    network::{send_lobby_message, LobbyMessage, LobbyRole, LobbyRoom},
+    ratings::Leaderboard,
+    relays, send_new_peer,
+    tasks::spawn,
+    GameState,
+};
+
+/// Parameterized replaceable, one seeking event per player
+pub const SEEK_KIND: u64 = 30_421;
+const SEEK_IDENTIFIER: &str = "quick-match";
+/// Seekers that stopped refreshing drop out after this
+const SEEK_TTL: Duration = Duration::from_secs(60);
+const SEEK_REFRESH: Duration = Duration::from_secs(20);
+/// Gives the subscription time to find everyone before we pair
+const PAIRING_DELAY: Duration = Duration::from_secs(3);
+/// The host's socket needs a moment before it hears our connection request
+const JOINER_DELAY: Duration = Duration::from_secs(2);
+/// Two seekers that saw different lists may pick different partners, after
+/// this we give up on the pair and search again
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// Seekers are sorted by `rating / RATING_BUCKET`, so a few points either way
+/// rarely reorders them and breaks up pairs
+const RATING_BUCKET: u32 = 200;
+
+/// Content of a seeking event
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Seeker {
+    pub mode: String,
+    pub version: String,
+    pub rating: u32,
+    /// Relay we'd signal through when we end up hosting
+    pub relay: String,
+    #[serde(default)]
+    pub cancelled: bool,
+    /// Who we paired with, they pair back even if their list looked different
+    #[serde(default)]
+    pub paired_with: Option<XOnlyPublicKey>,
+}
+
+/// A seeker we heard from, with the unix time their event expires at
+type Seen = (Seeker, u64);
+
+/// Forgets seekers whose event ran out, they went offline without cancelling
+fn drop_expired(seekers: &mut HashMap<XOnlyPublicKey, Seen>, now: u64) {
+    seekers.retain(|_, (_, expires_at)| *expires_at > now);
+}
+
+/// Who we pair with out of `seekers`, which includes ourselves. `None` while
+/// nobody is left over for us.
+pub fn pick_opponent(
+    me: XOnlyPublicKey,
+    seekers: &HashMap<XOnlyPublicKey, Seeker>,
+) -> Option<XOnlyPublicKey> {
+    let mine = seekers.get(&me)?;
+    let available: Vec<(&XOnlyPublicKey, &Seeker)> = seekers
+        .iter()
+        .filter(|(_, seeker)| {
+            !seeker.cancelled && seeker.mode == mine.mode && seeker.version == mine.version
+        })
+        .collect();
+    // someone already picked us
+    if let Some(picked_us) = available
+        .iter()
+        .filter(|(_, seeker)| seeker.paired_with == Some(me))
+        .map(|(public_key, _)| **public_key)
+        .min()
+    {
+        return Some(picked_us);
+    }
+    let taken: Vec<XOnlyPublicKey> = available
+        .iter()
+        .filter_map(|(public_key, seeker)| Some([**public_key, seeker.paired_with?]))
+        .flatten()
+        .collect();
+    let mut pool: Vec<(u32, XOnlyPublicKey)> = available
+        .into_iter()
+        .filter(|(public_key, _)| !taken.contains(*public_key))
+        .map(|(public_key, seeker)| (seeker.rating / RATING_BUCKET, *public_key))
+        .collect();
+    pool.sort();
+    let position = pool.iter().position(|(_, public_key)| *public_key == me)?;
+    let partner = if position % 2 == 0 {
+        position + 1
+    } else {
+        position - 1
+    };
+    pool.get(partner).map(|(_, public_key)| *public_key)
+}
+
+/// Set while we're in the queue
+#[derive(Resource)]
+pub struct QuickMatchSearch {
+    started: Instant,
+    /// Ours when we started, so the event doesn't change under the others
+    rating: u32,
+    last_published: Option<Instant>,
+    seekers: Arc<Mutex<HashMap<XOnlyPublicKey, Seen>>>,
+    client: Arc<Mutex<Option<Client>>>,
+}
+
+/// The opponent we got paired with, until the lobby room takes over
+#[derive(Resource, Debug)]
+pub struct QuickMatch {
+    pub opponent: XOnlyPublicKey,
+    paired_at: Instant,
+    requested: bool,
+}
+
+fn seeker(nostr: &Nostr, rating: u32) -> Seeker {
+    Seeker {
+        mode: MODES[0].to_string(),
+        version: GAME_VERSION.to_string(),
+        rating,
+        relay: nostr.relay.clone(),
+        cancelled: false,
+        paired_with: None,
+    }
+}
+
+fn publish_seeker(nostr: &Nostr, seeker: &Seeker) {
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+    let content = serde_json::to_string(seeker).expect("serializing seeker");
+    let ttl = if seeker.cancelled {
+        Duration::from_secs(1)
+    } else {
+        SEEK_TTL
+    };
+    let expiration = Timestamp::now().as_u64() + ttl.as_secs();
+    let tags = [
+        Tag::Generic(
+            TagKind::Custom("d".to_string()),
+            vec![SEEK_IDENTIFIER.to_string()],
+        ),
+        Tag::Generic(
+            TagKind::Custom("expiration".to_string()),
+            vec![expiration.to_string()],
+        ),
+    ];
+    spawn(async move {
+        let event = EventBuilder::new(Kind::from(SEEK_KIND), content, &tags)
+            .to_event(&keys)
+            .unwrap();
+        let client = relays::connect(&keys, &relays).await;
+        if let Err(e) = client.send_msg(ClientMessage::new_event(event)).await {
+            warn!("failed to publish quick match search: {e}");
+        }
+        client.disconnect().await.ok();
+    });
+}
+
+fn parse_seeker(event: &Event) -> Option<Seen> {
+    let expires_at = event
+        .tags
+        .iter()
+        .map(|tag| tag.as_vec())
+        .find(|tag| tag.len() >= 2 && tag[0] == "expiration")
+        .and_then(|tag| tag[1].parse::<u64>().ok())
+        .unwrap_or(event.created_at.as_u64() + SEEK_TTL.as_secs());
+    if event.kind != Kind::from(SEEK_KIND) || expires_at <= Timestamp::now().as_u64() {
+        return None;
+    }
+    Some((serde_json::from_str(&event.content).ok()?, expires_at))
+}
+
+pub fn start_quick_match(commands: &mut Commands, nostr: &Nostr, rating: u32) {
+    let search = QuickMatchSearch {
+        started: Instant::now(),
+        rating,
+        last_published: None,
+        seekers: Default::default(),
+        client: Default::default(),
+    };
+
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+    let seekers = search.seekers.clone();
+    let client_handle = search.client.clone();
+    spawn(async move {
+        let client = relays::connect(&keys, &relays).await;
+        let filter = Filter::new()
+            .kind(Kind::from(SEEK_KIND))
+            .since(Timestamp::now() - SEEK_TTL);
+        client.subscribe(vec![filter]).await;
+        *client_handle.lock().unwrap() = Some(client.clone());
+
+        client
+            .handle_notifications(move |notification| {
+                let seekers = seekers.clone();
+                async move {
+                    if let RelayPoolNotification::Event(_, event) = notification {
+                        let mut seekers = seekers.lock().unwrap();
+                        match parse_seeker(&event) {
+                            Some(seen) => seekers.insert(event.pubkey, seen),
+                            // a cancellation that took a while to get here
+                            None => seekers.remove(&event.pubkey),
+                        };
+                    }
+                    Ok(())
+                }
+            })
+            .await
+            .ok();
+    });
+    commands.insert_resource(search);
+}
+
+/// Leaves the queue, `last` is what the others see of us from now on
+fn stop_search(commands: &mut Commands, search: &QuickMatchSearch, nostr: &Nostr, last: &Seeker) {
+    publish_seeker(nostr, last);
+    if let Some(client) = search.client.lock().unwrap().take() {
+        spawn(async move {
+            client.shutdown().await.ok();
+        });
+    }
+    commands.remove_resource::<QuickMatchSearch>();
+}
+
+/// Keeps our seeking event alive and pairs us up once someone shows up
+pub fn quick_match_search(
+    mut commands: Commands,
+    mut search: ResMut<QuickMatchSearch>,
+    mut nostr_query: Query<&mut Nostr>,
+    mut role: ResMut<LobbyRole>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let mut nostr = nostr_query.single_mut();
+    let me = nostr.keys.public_key();
+    let mine = seeker(&nostr, search.rating);
+
+    let refresh_due = search
+        .last_published
+        .map_or(true, |published| published.elapsed() >= SEEK_REFRESH);
+    if refresh_due {
+        publish_seeker(&nostr, &mine);
+        search.last_published = Some(Instant::now());
+    }
+    let now = Timestamp::now().as_u64();
+    let seekers: HashMap<XOnlyPublicKey, Seeker> = {
+        let mut seen = search.seekers.lock().unwrap();
+        drop_expired(&mut seen, now);
+        // we may not hear our own event back from every relay
+        seen.insert(me, (mine.clone(), now + SEEK_TTL.as_secs()));
+        seen.iter()
+            .map(|(public_key, (seeker, _))| (*public_key, seeker.clone()))
+            .collect()
+    };
+
+    if search.started.elapsed() < PAIRING_DELAY {
+        return;
+    }
+    let Some(opponent) = pick_opponent(me, &seekers) else {
+        return;
+    };
+
+    let host = me < opponent;
+    info!("quick match paired with {opponent}, hosting: {host}");
+    *role = if host {
+        LobbyRole::Host
+    } else {
+        LobbyRole::Joiner
+    };
+    // the socket opens on entering Matchmaking, it has to go to the host's relay
+    if !host {
+        nostr.relay = seekers[&opponent].relay.clone();
+    }
+    commands.insert_resource(QuickMatch {
+        opponent,
+        paired_at: Instant::now(),
+        requested: host,
+    });
+    let paired = Seeker {
+        paired_with: Some(opponent),
+        ..mine
+    };
+    stop_search(&mut commands, &search, &nostr, &paired);
+    next_state.set(GameState::Matchmaking);
+}
+
+/// Does in the lobby room what players would click by hand: the host lets its
+/// partner in and both ready up
+#[allow(clippy::too_many_arguments)]
+pub fn quick_match_room(
+    mut commands: Commands,
+    mut quick: ResMut<QuickMatch>,
+    mut room: ResMut<LobbyRoom>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    nostr_query: Query<&Nostr>,
+    role: Res<LobbyRole>,
+    mut join_form: ResMut<JoinForm>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let opponent = PeerId(quick.opponent);
+
+    if !quick.requested && quick.paired_at.elapsed() >= JOINER_DELAY {
+        send_new_peer(nostr_query.single(), quick.opponent, None);
+        quick.requested = true;
+    }
+
+    let connected = socket.connected_peers().any(|peer| peer == opponent);
+    if *role == LobbyRole::Host && room.accepted.is_none() && connected {
+        room.accepted = Some(opponent);
+        send_lobby_message(&mut socket, opponent, &LobbyMessage::Accepted);
+    }
+    if room.accepted == Some(opponent) && !room.ready {
+        room.ready = true;
+        send_lobby_message(&mut socket, opponent, &LobbyMessage::Ready(true));
+    }
+
+    if room.accepted.is_none() && quick.paired_at.elapsed() >= CONNECT_TIMEOUT {
+        warn!("quick match partner never showed up");
+        join_form.error = Some("Quick match fell through, try again".to_string());
+        commands.remove_resource::<QuickMatch>();
+        next_state.set(GameState::Menu);
+    }
+}
+
+/// Paired players are in the match now
+pub fn forget_quick_match(mut commands: Commands) {
+    commands.remove_resource::<QuickMatch>();
+}
+
+pub fn quick_match_panel(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    search: Option<Res<QuickMatchSearch>>,
+    nostr_query: Query<&Nostr>,
+    leaderboard: Res<Leaderboard>,
+) {
+    let nostr = nostr_query.single();
+    egui::Window::new("Quick match")
+        .resizable(false)
+        .collapsible(false)
+        .anchor(egui::Align2::CENTER_TOP, egui::Vec2::new(0.0, 8.0))
+        .show(contexts.ctx_mut(), |ui| match &search {
+            None => {
+                if ui.button("Find me a match").clicked() {
+                    let rating = leaderboard.rating_of(&nostr.keys.public_key());
+                    start_quick_match(&mut commands, nostr, rating);
+                }
+            }
+            Some(search) => {
+                let others = search
+                    .seekers
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .filter(|(seeker, _)| !seeker.cancelled && seeker.paired_with.is_none())
+                    .count()
+                    .saturating_sub(1);
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label(format!(
+                        "Searching for an opponent... {}s ({others} others searching)",
+                        search.started.elapsed().as_secs()
+                    ));
+                });
+                if ui.button("Cancel").clicked() {
+                    let cancelled = Seeker {
+                        cancelled: true,
+                        ..seeker(nostr, search.rating)
+                    };
+                    stop_search(&mut commands, search, nostr, &cancelled);
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::Keys;
+
+    fn seeker(rating: u32) -> Seeker {
+        Seeker {
+            mode: MODES[0].to_string(),
+            version: GAME_VERSION.to_string(),
+            rating,
+            relay: "wss://relay.example.com".to_string(),
+            cancelled: false,
+            paired_with: None,
+        }
+    }
+
+    #[test]
+    fn pairs_agree_from_both_sides() {
+        let keys: Vec<XOnlyPublicKey> = (0..5).map(|_| Keys::generate().public_key()).collect();
+        let seekers: HashMap<_, _> = keys.iter().map(|key| (*key, seeker(1000))).collect();
+
+        let mut unpaired = 0;
+        for key in &keys {
+            match pick_opponent(*key, &seekers) {
+                Some(opponent) => {
+                    assert_ne!(opponent, *key);
+                    assert_eq!(pick_opponent(opponent, &seekers), Some(*key));
+                }
+                None => unpaired += 1,
+            }
+        }
+        assert_eq!(unpaired, 1);
+    }
+
+    #[test]
+    fn pairs_similar_ratings() {
+        let keys: Vec<XOnlyPublicKey> = (0..4).map(|_| Keys::generate().public_key()).collect();
+        let ratings = [1000, 1900, 1050, 1850];
+        let seekers: HashMap<_, _> = keys
+            .iter()
+            .zip(ratings)
+            .map(|(key, rating)| (*key, seeker(rating)))
+            .collect();
+
+        assert_eq!(pick_opponent(keys[0], &seekers), Some(keys[2]));
+        assert_eq!(pick_opponent(keys[1], &seekers), Some(keys[3]));
+    }
+
+    #[test]
+    fn skips_cancelled_and_other_modes() {
+        let me = Keys::generate().public_key();
+        let other = Keys::generate().public_key();
+        let mut seekers = HashMap::default();
+        seekers.insert(me, seeker(1000));
+        seekers.insert(
+            other,
+            Seeker {
+                cancelled: true,
+                ..seeker(1000)
+            },
+        );
+        assert_eq!(pick_opponent(me, &seekers), None);
+
+        seekers.insert(
+            other,
+            Seeker {
+                mode: "other".to_string(),
+                ..seeker(1000)
+            },
+        );
+        assert_eq!(pick_opponent(me, &seekers), None);
+    }
+
+    #[test]
+    fn late_seekers_pair_back() {
+        let mut keys: Vec<XOnlyPublicKey> = (0..3).map(|_| Keys::generate().public_key()).collect();
+        keys.sort();
+        let [late, third, early] = [keys[0], keys[1], keys[2]];
+
+        // the early seeker only saw the late one and paired with it
+        let mut seekers = HashMap::default();
+        seekers.insert(early, seeker(1000));
+        seekers.insert(late, seeker(1000));
+        assert_eq!(pick_opponent(early, &seekers), Some(late));
+        seekers.insert(
+            early,
+            Seeker {
+                paired_with: Some(late),
+                ..seeker(1000)
+            },
+        );
+
+        // the late one would pair with the third by its own list, and the
+        // third with the late one, but the pairing that was announced wins
+        seekers.insert(third, seeker(1000));
+        assert_eq!(pick_opponent(late, &seekers), Some(early));
+        assert_eq!(pick_opponent(third, &seekers), None);
+    }
+
+    #[test]
+    fn expired_seekers_drop_out() {
+        let gone = Keys::generate().public_key();
+        let waiting = Keys::generate().public_key();
+        let mut seen = HashMap::default();
+        seen.insert(gone, (seeker(1000), 100));
+        seen.insert(waiting, (seeker(1000), 200));
+
+        drop_expired(&mut seen, 150);
+        assert!(!seen.contains_key(&gone));
+        assert!(seen.contains_key(&waiting));
+    }
+}
@@ -0,0 +1,339 @@
+//! Elo ratings, worked out from scratch on every client out of the match
+//! results on the relays. Only results signed by both players count, see
+//! `results`. The ones still missing a countersignature are counted next to
+//! everyone involved, so refusing to sign a loss shows.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use bevy_egui::{egui, EguiContexts};
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{Event, EventId, Filter, Kind};
+
+use crate::{
+    components::Nostr,
+    profiles::ProfileUi,
+    relays,
+    results::{confirms, parse_proposal, proposal_id, MatchResult, RESULT_KIND},
+    tasks::spawn,
+};
+
+pub const DEFAULT_RATING: u32 = 1000;
+const K_FACTOR: f64 = 32.0;
+/// Results further back than this many events aren't fetched
+const RESULT_LIMIT: usize = 2000;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(8);
+const LEADERBOARD_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rating {
+    pub rating: f64,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+    /// Results only the other player signed
+    pub unconfirmed: u32,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Self {
+            rating: DEFAULT_RATING as f64,
+            wins: 0,
+            losses: 0,
+            draws: 0,
+            unconfirmed: 0,
+        }
+    }
+}
+
+impl Rating {
+    fn played(&self) -> u32 {
+        self.wins + self.losses + self.draws
+    }
+}
+
+/// Chance `rating` beats `opponent`
+fn expected(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+/// Replays `results` oldest first
+pub fn ratings<'a>(
+    results: impl IntoIterator<Item = &'a MatchResult>,
+) -> HashMap<XOnlyPublicKey, Rating> {
+    let mut ratings: HashMap<XOnlyPublicKey, Rating> = HashMap::new();
+    for result in results {
+        let [first, second] = result.players;
+        if first == second {
+            continue;
+        }
+        let a = ratings.get(&first).copied().unwrap_or_default();
+        let b = ratings.get(&second).copied().unwrap_or_default();
+        let score = match result.winner {
+            Some(winner) if winner == first => 1.0,
+            Some(winner) if winner == second => 0.0,
+            Some(_) => continue,
+            None => 0.5,
+        };
+        let change = K_FACTOR * (score - expected(a.rating, b.rating));
+        for (player, before, change, score) in
+            [(first, a, change, score), (second, b, -change, 1.0 - score)]
+        {
+            let rating = ratings.entry(player).or_insert(before);
+            rating.rating += change;
+            match score {
+                s if s > 0.5 => rating.wins += 1,
+                s if s < 0.5 => rating.losses += 1,
+                _ => rating.draws += 1,
+            }
+        }
+    }
+    ratings
+}
+
+/// Countersigned results by proposal
+fn confirmed(events: &[Event]) -> HashMap<EventId, (u64, MatchResult)> {
+    let proposals: HashMap<EventId, &Event> = events
+        .iter()
+        .filter(|event| proposal_id(event).is_none())
+        .map(|event| (event.id, event))
+        .collect();
+    let mut confirmed: HashMap<EventId, (u64, MatchResult)> = HashMap::new();
+    for countersign in events {
+        let Some(proposal) = proposal_id(countersign).and_then(|id| proposals.get(&id)) else {
+            continue;
+        };
+        if let Some(result) = confirms(proposal, countersign) {
+            confirmed.insert(proposal.id, (proposal.created_at.as_u64(), result));
+        }
+    }
+    confirmed
+}
+
+/// Pairs up proposals and countersignatures, oldest match first. Results with
+/// a single signature are dropped.
+pub fn confirmed_results(events: &[Event]) -> Vec<MatchResult> {
+    let mut confirmed: Vec<(u64, MatchResult)> = confirmed(events).into_values().collect();
+    confirmed.sort_by_key(|(created_at, _)| *created_at);
+    confirmed.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Proposals nobody countersigned, counted for both players
+pub fn unconfirmed_results(events: &[Event]) -> HashMap<XOnlyPublicKey, u32> {
+    let confirmed = confirmed(events);
+    let mut unconfirmed: HashMap<XOnlyPublicKey, u32> = HashMap::new();
+    for proposal in events {
+        if confirmed.contains_key(&proposal.id) {
+            continue;
+        }
+        let Some(result) = parse_proposal(proposal) else {
+            continue;
+        };
+        for player in result.players {
+            *unconfirmed.entry(player).or_default() += 1;
+        }
+    }
+    unconfirmed
+}
+
+/// Everyone's rating, refetched whenever the menu comes back
+#[derive(Resource, Default)]
+pub struct Leaderboard {
+    ratings: Arc<Mutex<Option<HashMap<XOnlyPublicKey, Rating>>>>,
+    fetched: Option<Instant>,
+}
+
+impl Leaderboard {
+    pub fn rating(&self, public_key: &XOnlyPublicKey) -> Option<Rating> {
+        self.ratings
+            .lock()
+            .unwrap()
+            .as_ref()?
+            .get(public_key)
+            .copied()
+    }
+
+    /// What quick match pairs us by
+    pub fn rating_of(&self, public_key: &XOnlyPublicKey) -> u32 {
+        self.rating(public_key).map_or(DEFAULT_RATING, |rating| {
+            rating.rating.round().max(0.0) as u32
+        })
+    }
+
+    fn fetch(&mut self, nostr: &Nostr) {
+        self.fetched = Some(Instant::now());
+        let keys = nostr.keys.clone();
+        let relays = nostr.relays.clone();
+        let ratings = self.ratings.clone();
+        spawn(async move {
+            let client = relays::connect(&keys, &relays).await;
+            let filter = Filter::new()
+                .kind(Kind::from(RESULT_KIND))
+                .limit(RESULT_LIMIT);
+            match client
+                .get_events_of(vec![filter], Some(FETCH_TIMEOUT))
+                .await
+            {
+                Ok(events) => {
+                    let results = confirmed_results(&events);
+                    info!(
+                        "{} confirmed results out of {}",
+                        results.len(),
+                        events.len()
+                    );
+                    let mut rated = self::ratings(&results);
+                    for (player, count) in unconfirmed_results(&events) {
+                        rated.entry(player).or_default().unconfirmed = count;
+                    }
+                    *ratings.lock().unwrap() = Some(rated);
+                }
+                Err(e) => warn!("failed to fetch match results: {e}"),
+            }
+            client.disconnect().await.ok();
+        });
+    }
+}
+
+/// Picks up the results of the match we just came back from
+pub fn refresh_leaderboard(mut leaderboard: ResMut<Leaderboard>) {
+    leaderboard.fetched = None;
+}
+
+pub fn leaderboard_panel(
+    mut contexts: EguiContexts,
+    mut leaderboard: ResMut<Leaderboard>,
+    nostr_query: Query<&Nostr>,
+    mut profile_ui: ProfileUi,
+) {
+    let nostr = nostr_query.single();
+    if leaderboard.fetched.is_none() {
+        leaderboard.fetch(nostr);
+    }
+    let me = nostr.keys.public_key();
+
+    egui::Window::new("Leaderboard")
+        .resizable(false)
+        .default_open(false)
+        .anchor(egui::Align2::LEFT_TOP, egui::Vec2::new(8.0, 8.0))
+        .show(contexts.ctx_mut(), |ui| {
+            let standings = leaderboard.ratings.lock().unwrap().clone();
+            let Some(standings) = standings else {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Fetching results...");
+                });
+                return;
+            };
+            // nobody gets on the board without a confirmed match
+            let mut standings: Vec<(XOnlyPublicKey, Rating)> = standings
+                .into_iter()
+                .filter(|(_, rating)| rating.played() > 0)
+                .collect();
+            standings.sort_by(|(_, a), (_, b)| b.rating.total_cmp(&a.rating));
+
+            if standings.is_empty() {
+                ui.label("No rated matches yet.");
+            }
+            egui::Grid::new("leaderboard").striped(true).show(ui, |ui| {
+                for (rank, (player, rating)) in standings.iter().take(LEADERBOARD_SIZE).enumerate()
+                {
+                    ui.label(format!("{}.", rank + 1));
+                    profile_ui.label(ui, *player);
+                    ui.label(format!("{:.0}", rating.rating));
+                    ui.weak(format!(
+                        "{}W {}L {}D",
+                        rating.wins, rating.losses, rating.draws
+                    ));
+                    if rating.unconfirmed > 0 {
+                        ui.weak(format!("{} unconfirmed", rating.unconfirmed))
+                            .on_hover_text("Results one of the players never countersigned");
+                    } else {
+                        ui.label("");
+                    }
+                    ui.end_row();
+                }
+            });
+
+            ui.separator();
+            let mine = leaderboard.rating(&me).unwrap_or_default();
+            ui.label(format!(
+                "Your rating: {:.0} ({}W {}L {}D)",
+                mine.rating, mine.wins, mine.losses, mine.draws
+            ));
+            if mine.unconfirmed > 0 {
+                ui.weak(format!(
+                    "{} of your results are unconfirmed",
+                    mine.unconfirmed
+                ));
+            }
+            if ui.small_button("Refresh").clicked() {
+                leaderboard.fetch(nostr);
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::{serde_json, EventBuilder, Keys, Tag, TagKind};
+
+    #[test]
+    fn winner_gains_what_loser_drops() {
+        let (a, b) = (Keys::generate().public_key(), Keys::generate().public_key());
+        let result = MatchResult {
+            players: [a, b],
+            winner: Some(a),
+            version: String::new(),
+        };
+        let after = ratings([&result]);
+        assert_eq!(after[&a].rating, DEFAULT_RATING as f64 + K_FACTOR / 2.0);
+        assert_eq!(after[&b].rating, DEFAULT_RATING as f64 - K_FACTOR / 2.0);
+        assert_eq!((after[&a].wins, after[&b].losses), (1, 1));
+
+        let draw = MatchResult {
+            winner: None,
+            ..result
+        };
+        let after = ratings([&draw]);
+        assert_eq!(after[&a].rating, DEFAULT_RATING as f64);
+        assert_eq!(after[&b].draws, 1);
+    }
+
+    #[test]
+    fn withheld_countersigns_show() {
+        let (a, b) = (Keys::generate(), Keys::generate());
+        let win = MatchResult {
+            players: [a.public_key(), b.public_key()],
+            winner: Some(a.public_key()),
+            version: String::new(),
+        };
+        let draw = MatchResult {
+            winner: None,
+            ..win.clone()
+        };
+        let sign = |keys: &Keys, result: &MatchResult, proposal: Option<EventId>| {
+            let content = serde_json::to_string(result).unwrap();
+            let tags: Vec<Tag> = proposal
+                .map(|proposal| Tag::Generic(TagKind::E, vec![proposal.to_hex()]))
+                .into_iter()
+                .collect();
+            EventBuilder::new(Kind::from(RESULT_KIND), content, &tags)
+                .to_event(keys)
+                .unwrap()
+        };
+
+        let proposal = sign(&a, &win, None);
+        let countersign = sign(&b, &win, Some(proposal.id));
+        let withheld = sign(&a, &draw, None);
+        let events = [proposal, countersign, withheld];
+
+        assert_eq!(confirmed_results(&events), vec![win]);
+        let unconfirmed = unconfirmed_results(&events);
+        assert_eq!(unconfirmed[&a.public_key()], 1);
+        assert_eq!(unconfirmed[&b.public_key()], 1);
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use bevy_ggrs::{RollbackIdProvider, Session};
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::prelude::{FromBech32, ToBech32};
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{serde_json, Timestamp};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::{
+        Bullet, BulletReady, Health, MoveDir, Nostr, Player, PlayerStats, Position, Target,
+    },
+    fixed::{Fixed, FixedVec2},
+    game_server::ServerConnection,
+    net_stats::FrameCount,
+    network::{
+        forfeit, open_socket, send_lobby_message, start_session, GgrsChannel, Interruption,
+        LobbyMessage, NetSettings, MAX_PREDICTION,
+    },
+    spells::spawn_bullet,
+    storage, GgrsConfig, ImageAssets,
+};
+
+const ACTIVE_MATCH_KEY: &str = "fightgame.active_match";
+/// How long the surviving peer holds the match open for the other one to come back
+pub const RECONNECT_WINDOW: Duration = Duration::from_secs(30);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Kept in storage while a match runs, so a reloaded tab can find its way back.
+/// Our key persists across reloads, matchbox-nostr signals as the same peer.
+#[derive(Resource, Serialize, Deserialize, Debug, Clone)]
+pub struct ActiveMatch {
+    /// npub we played as, a different imported key can't rejoin
+    pub player: String,
+    /// npub of the opponent
+    pub opponent: String,
+    /// unix seconds, refreshed while the match runs
+    pub last_seen: u64,
+    /// Signalling relay of the match, the opponent waits for us there
+    #[serde(default)]
+    pub relay: String,
+}
+
+impl ActiveMatch {
+    /// The stored match, if it was still alive recently enough to rejoin
+    pub fn load() -> Option<Self> {
+        let record: Self = serde_json::from_str(&storage::load(ACTIVE_MATCH_KEY)?).ok()?;
+        let age = Timestamp::now().as_u64().saturating_sub(record.last_seen);
+        if age > RECONNECT_WINDOW.as_secs() {
+            Self::clear();
+            return None;
+        }
+        Some(record)
+    }
+
+    pub fn save(&self) {
+        let record = serde_json::to_string(self).expect("serializing active match");
+        storage::save(ACTIVE_MATCH_KEY, &record);
+    }
+
+    pub fn clear() {
+        storage::remove(ACTIVE_MATCH_KEY);
+    }
+
+    pub fn opponent(&self) -> Option<XOnlyPublicKey> {
+        XOnlyPublicKey::from_bech32(&self.opponent).ok()
+    }
+}
+
+/// We reloaded mid match, offered in the menu
+#[derive(Resource, Debug)]
+pub struct Rejoin {
+    pub opponent: XOnlyPublicKey,
+    pub relay: String,
+}
+
+/// The peer is gone from the GGRS session, either timed out or the WebRTC
+/// connection closed
+#[derive(Debug)]
+pub struct OpponentLeft {
+    pub peer: PeerId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlayerSnapshot {
+    pub handle: usize,
+    pub position: FixedVec2,
+    pub target: FixedVec2,
+    pub move_dir: FixedVec2,
+    pub moving: bool,
+    pub health: u32,
+    pub max_health: u32,
+    pub bullet_ready: bool,
+    #[serde(default)]
+    pub stats: PlayerStats,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BulletSnapshot {
+    pub shooter: usize,
+    pub position: FixedVec2,
+    pub move_dir: FixedVec2,
+    pub traveled: Fixed,
+}
+
+/// The whole simulation at the last frame both peers had confirmed inputs for
+/// when the opponent dropped.
+///
+/// Both peers start a fresh GGRS session from it, so no input history has to be
+/// replayed on top. Whatever was only predicted after it is dropped.
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MatchSnapshot {
+    pub frame: u32,
+    pub settings: NetSettings,
+    pub players: Vec<PlayerSnapshot>,
+    pub bullets: Vec<BulletSnapshot>,
+}
+
+/// Held by the surviving peer while the other one is gone
+#[derive(Resource, Debug)]
+pub struct AwaitingReconnect {
+    pub opponent: PeerId,
+    pub deadline: Instant,
+    pub snapshot: MatchSnapshot,
+}
+
+pub fn remember_match(
+    mut commands: Commands,
+    nostr_query: Query<&Nostr>,
+    socket: Res<MatchboxSocket<MultipleChannels>>,
+) {
+    let Some(opponent) = socket.connected_peers().next() else {
+        return;
+    };
+    remember(&mut commands, nostr_query.single(), opponent);
+}
+
+/// Stores the match we just started playing
+pub fn remember(commands: &mut Commands, nostr: &Nostr, opponent: PeerId) {
+    let active = ActiveMatch {
+        player: nostr.keys.public_key().to_bech32().unwrap(),
+        opponent: opponent.0.to_bech32().unwrap(),
+        last_seen: Timestamp::now().as_u64(),
+        relay: nostr.relay.clone(),
+    };
+    active.save();
+    commands.insert_resource(active);
+}
+
+pub fn heartbeat_active_match(
+    mut active: ResMut<ActiveMatch>,
+    mut last_beat: Local<Option<Instant>>,
+) {
+    if last_beat.map_or(false, |beat| beat.elapsed() < HEARTBEAT_INTERVAL) {
+        return;
+    }
+    *last_beat = Some(Instant::now());
+    active.last_seen = Timestamp::now().as_u64();
+    active.save();
+}
+
+pub fn forget_match(mut commands: Commands) {
+    ActiveMatch::clear();
+    commands.remove_resource::<ActiveMatch>();
+    commands.remove_resource::<AwaitingReconnect>();
+}
+
+type PlayerState<'a> = (
+    &'a Player,
+    &'a Position,
+    &'a Target,
+    &'a MoveDir,
+    &'a Health,
+    &'a BulletReady,
+    &'a PlayerStats,
+);
+
+fn snapshot(
+    frame: u32,
+    settings: NetSettings,
+    players: &Query<PlayerState>,
+    bullets: &Query<(&Bullet, &Position, &MoveDir)>,
+) -> MatchSnapshot {
+    MatchSnapshot {
+        frame,
+        settings,
+        players: players
+            .iter()
+            .map(
+                |(player, position, target, move_dir, health, bullet_ready, stats)| {
+                    PlayerSnapshot {
+                        handle: player.handle,
+                        position: position.0,
+                        target: target.0,
+                        move_dir: move_dir.0,
+                        moving: player.moving,
+                        health: health.current,
+                        max_health: health.max,
+                        bullet_ready: bullet_ready.ready,
+                        stats: *stats,
+                    }
+                },
+            )
+            .collect(),
+        bullets: bullets
+            .iter()
+            .filter(|(bullet, _, _)| !bullet.despawned)
+            .map(|(bullet, position, move_dir)| BulletSnapshot {
+                shooter: bullet.shooter,
+                position: position.0,
+                move_dir: move_dir.0,
+                traveled: bullet.traveled,
+            })
+            .collect(),
+    }
+}
+
+/// The last few simulated frames, by `FrameCount`. Resimulating a frame
+/// replaces it, so once its inputs are confirmed the entry is too.
+#[derive(Resource, Default, Debug)]
+pub struct StateHistory {
+    frames: VecDeque<MatchSnapshot>,
+}
+
+impl StateHistory {
+    /// GGRS never runs further ahead of the confirmed frame than this
+    const LEN: usize = MAX_PREDICTION + 2;
+
+    fn push(&mut self, snapshot: MatchSnapshot) {
+        // a rollback or a new session starts counting from an earlier frame
+        self.frames.retain(|kept| kept.frame < snapshot.frame);
+        self.frames.push_back(snapshot);
+        if self.frames.len() > Self::LEN {
+            self.frames.pop_front();
+        }
+    }
+
+    fn get(&self, frame: u32) -> Option<&MatchSnapshot> {
+        self.frames.iter().find(|kept| kept.frame == frame)
+    }
+}
+
+/// Runs last in the rollback schedule
+pub fn record_state(
+    mut history: ResMut<StateHistory>,
+    frame: Res<FrameCount>,
+    settings: Res<NetSettings>,
+    players: Query<PlayerState>,
+    bullets: Query<(&Bullet, &Position, &MoveDir)>,
+) {
+    history.push(snapshot(frame.0, *settings, &players, &bullets));
+}
+
+/// `FrameCount` of the state after the last frame with every input confirmed
+fn confirmed_frame(frame: &FrameCount, session: Option<&Session<GgrsConfig>>) -> u32 {
+    let Some(Session::P2PSession(session)) = session else {
+        return frame.0;
+    };
+    let unconfirmed = session.current_frame() - session.confirmed_frame() - 1;
+    frame.0.wrapping_sub(unconfirmed.max(0) as u32)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn on_opponent_left(
+    mut commands: Commands,
+    mut events: EventReader<OpponentLeft>,
+    players: Query<PlayerState>,
+    bullets: Query<(&Bullet, &Position, &MoveDir)>,
+    frame: Res<FrameCount>,
+    settings: Res<NetSettings>,
+    history: Res<StateHistory>,
+    session: Option<Res<Session<GgrsConfig>>>,
+    nostr_query: Query<&Nostr>,
+) {
+    // the socket and GGRS usually both report it, only the first one counts
+    let Some(peer) = events.iter().next().map(|event| event.peer) else {
+        return;
+    };
+    events.clear();
+
+    // our world may already include predicted inputs the opponent never sent
+    let confirmed = confirmed_frame(&frame, session.as_deref());
+    let snapshot = match history.get(confirmed) {
+        Some(snapshot) => snapshot.clone(),
+        None => {
+            warn!(
+                "no state kept for confirmed frame {confirmed}, using frame {}",
+                frame.0
+            );
+            snapshot(frame.0, *settings, &players, &bullets)
+        }
+    };
+
+    warn!("opponent {peer:?} left, holding the match for them");
+    // no session means no simulation, the world stays frozen at the snapshot
+    commands.remove_resource::<Session<GgrsConfig>>();
+    commands.remove_resource::<GgrsChannel>();
+    commands.remove_resource::<ServerConnection>();
+    commands.remove_resource::<Interruption>();
+    // GGRS owns the old socket's game channel, the rejoin needs a fresh one
+    commands.remove_resource::<MatchboxSocket<MultipleChannels>>();
+    open_socket(&mut commands, nostr_query.single());
+    commands.insert_resource(AwaitingReconnect {
+        opponent: peer,
+        deadline: Instant::now() + RECONNECT_WINDOW,
+        snapshot,
+    });
+}
+
+pub fn await_reconnect(
+    mut commands: Commands,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    awaiting: Res<AwaitingReconnect>,
+    nostr_query: Query<&Nostr>,
+) {
+    for (peer, new_state) in socket.update_peers() {
+        if !matches!(new_state, PeerState::Connected) || peer != awaiting.opponent {
+            continue;
+        }
+        info!("opponent {peer:?} is back, resuming");
+        let snapshot = awaiting.snapshot.clone();
+        send_lobby_message(
+            &mut socket,
+            peer,
+            &LobbyMessage::Resume(Box::new(snapshot.clone())),
+        );
+        let local = PeerId(nostr_query.single().keys.public_key());
+        start_session(&mut commands, &mut socket, local, peer, snapshot.settings);
+        commands.remove_resource::<AwaitingReconnect>();
+        return;
+    }
+
+    if Instant::now() >= awaiting.deadline {
+        commands.remove_resource::<AwaitingReconnect>();
+        forfeit(&mut commands, awaiting.opponent);
+    }
+}
+
+/// Puts the freshly spawned players back where the snapshot had them
+pub fn restore_snapshot(
+    commands: &mut Commands,
+    rip: &mut RollbackIdProvider,
+    images: &ImageAssets,
+    snapshot: &MatchSnapshot,
+    players: &[Entity],
+) {
+    for player in &snapshot.players {
+        let Some(entity) = players.get(player.handle) else {
+            continue;
+        };
+        commands.entity(*entity).insert((
+            Player {
+                handle: player.handle,
+                moving: player.moving,
+            },
+            Position(player.position),
+            Target(player.target),
+            MoveDir(player.move_dir),
+            Health {
+                current: player.health,
+                max: player.max_health,
+            },
+            BulletReady {
+                ready: player.bullet_ready,
+            },
+            player.stats,
+        ));
+    }
+
+    for bullet in &snapshot.bullets {
+        spawn_bullet(
+            commands,
+            rip,
+            images,
+            Bullet {
+                shooter: bullet.shooter,
+                traveled: bullet.traveled,
+                despawned: false,
+                hit: false,
+            },
+            bullet.position,
+            bullet.move_dir,
+        );
+    }
+
+    commands.insert_resource(FrameCount(snapshot.frame));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(frame: u32) -> MatchSnapshot {
+        MatchSnapshot {
+            frame,
+            settings: NetSettings::default(),
+            players: Vec::new(),
+            bullets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn history_keeps_the_latest_run_of_each_frame() {
+        let mut history = StateHistory::default();
+        for frame in 1..=5 {
+            history.push(at(frame));
+        }
+        // rolled back to 3 and resimulated with other inputs
+        let mut resimulated = at(3);
+        resimulated.bullets.push(BulletSnapshot {
+            shooter: 0,
+            position: FixedVec2::ZERO,
+            move_dir: FixedVec2::X,
+            traveled: Fixed::ZERO,
+        });
+        history.push(resimulated.clone());
+        assert_eq!(history.get(3), Some(&resimulated));
+        assert_eq!(history.get(4), None);
+
+        for frame in 4..100 {
+            history.push(at(frame));
+        }
+        assert_eq!(history.frames.len(), StateHistory::LEN);
+        assert!(history.get(3).is_none());
+        assert_eq!(history.get(99), Some(&at(99)));
+    }
+}
@@ -0,0 +1,296 @@
+//! The relays we talk to. Lobby events go out to all of them, the WebRTC
+//! signalling needs a single one both peers share, so it uses the first relay
+//! that answers and the listing tells joiners which one that is.
+//!
+//! A host still waiting for players moves on to the next healthy relay when
+//! its signalling relay goes down and relists there. Private games, joiners
+//! and quick matches signal through the relay the other side was told about,
+//! so they have nothing to fail over to.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::{HashMap, Instant};
+use bevy_egui::{egui, EguiContexts};
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::{serde_json, Client, Filter, Keys, RelayStatus};
+
+use crate::{
+    components::Nostr,
+    identity::ExtensionIdentity,
+    lobby::{publish_listing, HostedGame},
+    network::{open_socket, LobbyRoom},
+    storage,
+    tasks::spawn,
+};
+
+const RELAYS_KEY: &str = "fightgame.relays";
+pub const DEFAULT_RELAYS: &[&str] = &[
+    "wss://nostr.lu.ke",
+    "wss://relay.damus.io",
+    "wss://nos.lol",
+    "wss://relay.snort.social",
+];
+/// Where `cargo run --bin local_relay --features local-relay` listens
+pub const LOCAL_RELAY: &str = "ws://127.0.0.1:7447";
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// The configured relays, or the defaults if none were saved
+pub fn load_relays() -> Vec<String> {
+    storage::load(RELAYS_KEY)
+        .and_then(|relays| serde_json::from_str::<Vec<String>>(&relays).ok())
+        .filter(|relays| !relays.is_empty())
+        .unwrap_or_else(default_relays)
+}
+
+pub fn save_relays(relays: &[String]) {
+    let record = serde_json::to_string(relays).expect("serializing relays");
+    storage::save(RELAYS_KEY, &record);
+}
+
+pub fn default_relays() -> Vec<String> {
+    DEFAULT_RELAYS
+        .iter()
+        .map(|relay| relay.to_string())
+        .collect()
+}
+
+/// A client connected to every relay in `relays`, unusable ones are skipped
+pub async fn connect(keys: &Keys, relays: &[String]) -> Client {
+    let client = Client::new(keys);
+    for relay in relays {
+        #[cfg(target_arch = "wasm32")]
+        let added = client.add_relay(relay.as_str()).await;
+        #[cfg(not(target_arch = "wasm32"))]
+        let added = client.add_relay(relay.as_str(), None).await;
+        if let Err(e) = added {
+            warn!("skipping relay {relay}: {e}");
+        }
+    }
+    client.connect().await;
+    client
+}
+
+/// Whether the relay answered a tiny query recently, `None` while the first
+/// check is still running
+#[derive(Resource, Default, Clone)]
+pub struct RelayHealth {
+    status: Arc<Mutex<HashMap<String, Option<bool>>>>,
+    /// How long the last successful check took, the closest thing to a ping
+    /// to the host we have before connecting
+    latency: Arc<Mutex<HashMap<String, Duration>>>,
+}
+
+impl RelayHealth {
+    pub fn get(&self, relay: &str) -> Option<bool> {
+        self.status.lock().unwrap().get(relay).copied().flatten()
+    }
+
+    pub fn latency(&self, relay: &str) -> Option<Duration> {
+        // urls from the relay pool come with a trailing slash
+        let relay = relay.trim_end_matches('/');
+        self.latency.lock().unwrap().get(relay).copied()
+    }
+
+    fn checked(&self, relay: &str) -> bool {
+        self.status.lock().unwrap().contains_key(relay)
+    }
+}
+
+pub fn check_relays(
+    health: Res<RelayHealth>,
+    nostr_query: Query<&Nostr>,
+    mut last_check: Local<Option<Instant>>,
+) {
+    let Ok(nostr) = nostr_query.get_single() else {
+        return;
+    };
+    // relays added since the last round get checked straight away
+    let unchecked: Vec<String> = nostr
+        .relays
+        .iter()
+        .filter(|relay| !health.checked(relay))
+        .cloned()
+        .collect();
+    let due = last_check.map_or(true, |check| check.elapsed() >= HEALTH_CHECK_INTERVAL);
+    let relays = if due { nostr.relays.clone() } else { unchecked };
+    if due {
+        *last_check = Some(Instant::now());
+    }
+
+    for relay in relays {
+        let keys = nostr.keys.clone();
+        let health = health.clone();
+        // marked before the check finishes, so it isn't started twice
+        health
+            .status
+            .lock()
+            .unwrap()
+            .entry(relay.clone())
+            .or_insert(None);
+        spawn(async move {
+            let started = Instant::now();
+            let client = connect(&keys, std::slice::from_ref(&relay)).await;
+            let events = client
+                .get_events_of(vec![Filter::new().limit(1)], Some(HEALTH_CHECK_TIMEOUT))
+                .await;
+            // a freshly started relay has nothing to hand out, but answers with
+            // EOSE right away. Without one the query runs into the timeout.
+            let answered = started.elapsed() < HEALTH_CHECK_TIMEOUT;
+            let alive = connected(&client).await
+                && events.map_or(false, |events| !events.is_empty() || answered);
+            client.disconnect().await.ok();
+            if alive {
+                let latency = started.elapsed();
+                health
+                    .latency
+                    .lock()
+                    .unwrap()
+                    .insert(relay.clone(), latency);
+            } else {
+                warn!("relay {relay} is not responding");
+                health.latency.lock().unwrap().remove(&relay);
+            }
+            health.status.lock().unwrap().insert(relay, Some(alive));
+        });
+    }
+}
+
+async fn connected(client: &Client) -> bool {
+    for relay in client.relays().await.values() {
+        if relay.status().await == RelayStatus::Connected {
+            return true;
+        }
+    }
+    false
+}
+
+/// Signals through the first relay that is up, falling back to the first one
+/// configured while nothing has answered yet
+pub fn pick_signalling_relay(mut nostr_query: Query<&mut Nostr>, health: Res<RelayHealth>) {
+    let Ok(mut nostr) = nostr_query.get_single_mut() else {
+        return;
+    };
+    let relay = nostr
+        .relays
+        .iter()
+        .find(|relay| health.get(relay) == Some(true))
+        .or_else(|| nostr.relays.first())
+        .cloned();
+    if let Some(relay) = relay {
+        if relay != nostr.relay {
+            info!("signalling through {relay}");
+            nostr.relay = relay;
+        }
+    }
+}
+
+/// Moves a public game to the next healthy relay while nobody is connected yet
+pub fn fail_over_signalling(
+    mut commands: Commands,
+    mut nostr_query: Query<&mut Nostr>,
+    health: Res<RelayHealth>,
+    hosted: Res<HostedGame>,
+    room: Res<LobbyRoom>,
+    extension: Res<ExtensionIdentity>,
+) {
+    let mut nostr = nostr_query.single_mut();
+    if hosted.private || room.accepted.is_some() || health.get(&nostr.relay) != Some(false) {
+        return;
+    }
+    let Some(relay) = nostr
+        .relays
+        .iter()
+        .find(|relay| health.get(relay) == Some(true))
+        .cloned()
+    else {
+        return;
+    };
+    warn!(
+        "signalling relay {} went down, moving to {relay}",
+        nostr.relay
+    );
+    nostr.relay = relay;
+    commands.remove_resource::<MatchboxSocket<MultipleChannels>>();
+    open_socket(&mut commands, &nostr);
+    // the listing names the relay
+    publish_listing(&nostr, &extension, &hosted);
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct RelayForm {
+    pub new_relay: String,
+}
+
+pub fn relay_settings(
+    mut contexts: EguiContexts,
+    mut nostr_query: Query<&mut Nostr>,
+    mut form: ResMut<RelayForm>,
+    health: Res<RelayHealth>,
+) {
+    let mut nostr = nostr_query.single_mut();
+    let mut changed = false;
+
+    egui::Window::new("Relays")
+        .resizable(false)
+        .default_open(false)
+        .anchor(egui::Align2::RIGHT_BOTTOM, egui::Vec2::new(-8.0, -8.0))
+        .show(contexts.ctx_mut(), |ui| {
+            let mut remove = None;
+            for (i, relay) in nostr.relays.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    let (color, status) = match health.get(relay) {
+                        Some(true) => (egui::Color32::LIGHT_GREEN, "online"),
+                        Some(false) => (egui::Color32::RED, "not responding"),
+                        None => (egui::Color32::GRAY, "checking"),
+                    };
+                    ui.colored_label(color, "●").on_hover_text(status);
+                    ui.monospace(relay);
+                    if *relay == nostr.relay {
+                        ui.weak("(signalling)");
+                    }
+                    // the last relay stays, we can't do anything without one
+                    if nostr.relays.len() > 1 && ui.small_button("✖").clicked() {
+                        remove = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = remove {
+                nostr.relays.remove(i);
+                changed = true;
+            }
+
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut form.new_relay).hint_text("wss://..."));
+                let relay = form.new_relay.trim().to_string();
+                let valid = (relay.starts_with("wss://") || relay.starts_with("ws://"))
+                    && !nostr.relays.contains(&relay);
+                if ui.add_enabled(valid, egui::Button::new("Add")).clicked() {
+                    nostr.relays.push(relay);
+                    form.new_relay.clear();
+                    changed = true;
+                }
+            });
+
+            ui.horizontal(|ui| {
+                if ui.small_button("Reset to defaults").clicked() {
+                    nostr.relays = default_relays();
+                    changed = true;
+                }
+                if ui
+                    .small_button("Local relay")
+                    .on_hover_text("Play offline or on a LAN, see the local_relay binary")
+                    .clicked()
+                {
+                    nostr.relays = vec![LOCAL_RELAY.to_string()];
+                    changed = true;
+                }
+            });
+        });
+
+    if changed {
+        save_relays(&nostr.relays);
+    }
+}
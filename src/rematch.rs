@@ -0,0 +1,73 @@
+//! Playing the same opponent again without going back to the menu.
+//!
+//! Both players ask from the match over window (see `results`) once the result
+//! is signed and published, and each one drops its GGRS session on asking, so
+//! the old match stops sending inputs. Once both did, the world is reset and a
+//! new session starts on the same WebRTC connection.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use bevy_ggrs::RollbackIdProvider;
+use bevy_matchbox_nostr::prelude::*;
+
+use crate::{
+    components::{Bullet, FallenStats, Nostr, Player},
+    net_stats::{FrameCount, NetStatsOverlay, SimSteps},
+    network::{start_session_on, GgrsChannel, Interruption, NetSettings},
+    reconnect::remember,
+    results::MatchOver,
+    spawn_fighters,
+    time_sync::TimeSync,
+    ImageAssets, LocalPlayerHandle,
+};
+
+/// Gives packets of the old session still on the wire time to arrive, so they
+/// get drained instead of confusing the new one
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+#[allow(clippy::too_many_arguments)]
+pub fn start_rematch(
+    mut commands: Commands,
+    mut over: ResMut<MatchOver>,
+    channel: Res<GgrsChannel>,
+    local: Res<LocalPlayerHandle>,
+    settings: Res<NetSettings>,
+    mut rip: ResMut<RollbackIdProvider>,
+    images: Res<ImageAssets>,
+    entities: Query<Entity, Or<(With<Player>, With<Bullet>)>>,
+    nostr_query: Query<&Nostr>,
+) {
+    // `MatchOver` goes away below, the result has to be published first
+    if !over.rematch || !over.opponent_rematch || over.opponent_left || !over.signed() {
+        return;
+    }
+    let agreed = *over.agreed.get_or_insert_with(Instant::now);
+    if agreed.elapsed() < SETTLE_TIME {
+        return;
+    }
+
+    let nostr = nostr_query.single();
+    let me = PeerId(nostr.keys.public_key());
+    let opponent = PeerId(over.result.players[1 - local.0]);
+    info!("starting a rematch against {opponent:?}");
+
+    channel.drain();
+    for entity in entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    // both sides hand out the same ids again
+    *rip = RollbackIdProvider::default();
+    spawn_fighters(&mut commands, &mut rip, &images);
+    commands.insert_resource(FrameCount::default());
+    commands.insert_resource(FallenStats::default());
+    commands.insert_resource(SimSteps::default());
+    commands.insert_resource(NetStatsOverlay::default());
+    commands.insert_resource(TimeSync::default());
+    commands.remove_resource::<Interruption>();
+
+    start_session_on(&mut commands, channel.clone(), me, opponent, *settings);
+    remember(&mut commands, nostr, opponent);
+    commands.remove_resource::<MatchOver>();
+}
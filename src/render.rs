@@ -0,0 +1,84 @@
+use bevy::prelude::*;
+
+use crate::{
+    components::{Bullet, Health, MoveDir, Player, Position},
+    fixed::Fixed,
+};
+
+/// How quickly a drawn player catches up with its simulated position, per second
+const SMOOTHING_RATE: f32 = 20.0;
+/// Corrections bigger than this are snapped to instead of smoothed over
+const SNAP_DISTANCE: f32 = 2.0;
+const HIT_FLASH_SECONDS: f32 = 0.15;
+
+/// Visual only, tints the sprite for a moment when its health drops. Not
+/// registered for rollback, a mispredicted hit just flashes.
+#[derive(Component)]
+pub struct HitFlash {
+    pub last_health: u32,
+    pub timer: Timer,
+}
+
+impl HitFlash {
+    pub fn new(health: u32) -> Self {
+        let mut timer = Timer::from_seconds(HIT_FLASH_SECONDS, TimerMode::Once);
+        timer.tick(timer.duration());
+        Self {
+            last_health: health,
+            timer,
+        }
+    }
+}
+
+/// Moves sprites towards the simulation state. Runs outside the rollback
+/// schedule, nothing in gameplay should read `Transform`.
+///
+/// Players are eased so rollback corrections slide instead of snapping, bullets
+/// are too fast to smooth and just follow their position.
+pub fn sync_transforms(
+    time: Res<Time>,
+    mut player_query: Query<(&Position, &MoveDir, &mut Transform), (With<Player>, Without<Bullet>)>,
+    mut bullet_query: Query<(&Position, &MoveDir, &mut Transform), (With<Bullet>, Without<Player>)>,
+) {
+    let blend = 1.0 - (-SMOOTHING_RATE * time.delta_seconds()).exp();
+
+    for (position, move_dir, mut transform) in player_query.iter_mut() {
+        let target = position.0.to_vec2();
+        let current = transform.translation.truncate();
+        let pos = if current.distance(target) > SNAP_DISTANCE {
+            target
+        } else {
+            current.lerp(target, blend)
+        };
+        transform.translation.x = pos.x;
+        transform.translation.y = pos.y;
+        transform.rotation = if move_dir.0.x > Fixed::ZERO {
+            Quat::from_rotation_y(std::f32::consts::PI)
+        } else {
+            Quat::from_rotation_y(0.0)
+        };
+    }
+
+    for (position, move_dir, mut transform) in bullet_query.iter_mut() {
+        let pos = position.0.to_vec2();
+        transform.translation.x = pos.x;
+        transform.translation.y = pos.y;
+        transform.rotation = Quat::from_rotation_arc_2d(Vec2::X, move_dir.0.to_vec2());
+    }
+}
+
+pub fn flash_on_hit(time: Res<Time>, mut query: Query<(&Health, &mut HitFlash, &mut Sprite)>) {
+    for (health, mut flash, mut sprite) in query.iter_mut() {
+        if health.current < flash.last_health {
+            flash.timer.reset();
+        }
+        flash.last_health = health.current;
+
+        flash.timer.tick(time.delta());
+        sprite.color = if flash.timer.finished() {
+            Color::WHITE
+        } else {
+            Color::RED
+        };
+    }
+}
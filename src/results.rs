@@ -0,0 +1,415 @@
+//! How a match ends and the result both players sign.
+//!
+//! The winner signs the result, publishes it and sends it over the lobby
+//! channel, on a draw the player with handle 0 does. The other one checks it
+//! against its own simulation and signs a second event pointing at it. Only
+//! results carrying both signatures count towards ratings, so nobody can claim
+//! a win on their own. A loser who never countersigns doesn't get away with it
+//! either, the leaderboard shows everyone's unconfirmed results.
+//!
+//! The same lobby messages carry the rematch handshake, see `rematch`.
+
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use bevy_egui::{egui, EguiContexts};
+use bevy_ggrs::Session;
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::secp256k1::XOnlyPublicKey;
+use nostr_sdk::{serde_json, ClientMessage, Event, EventBuilder, EventId, Kind, Tag, TagKind};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    components::{stats_by_handle, FallenStats, Health, Nostr, Player, PlayerStats},
+    lobby::GAME_VERSION,
+    net_stats::FrameCount,
+    network::{receive_lobby_messages, send_lobby_message, LobbyMessage},
+    profiles::PlayerKeys,
+    reconnect::ActiveMatch,
+    relays,
+    tasks::spawn,
+    GameState, GgrsConfig, LocalPlayerHandle,
+};
+
+/// Regular kind, every match gets its own pair of events
+pub const RESULT_KIND: u64 = 4_420;
+
+/// Content of both result events
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MatchResult {
+    /// Ordered like the player handles
+    pub players: [XOnlyPublicKey; 2],
+    /// `None` when both went down on the same frame
+    pub winner: Option<XOnlyPublicKey>,
+    pub version: String,
+}
+
+impl MatchResult {
+    /// Who signs first, the one who'd want the result out
+    pub fn proposer(&self) -> XOnlyPublicKey {
+        self.winner.unwrap_or(self.players[0])
+    }
+
+    fn to_event(&self, nostr: &Nostr, proposal: Option<EventId>) -> Option<Event> {
+        let content = serde_json::to_string(self).expect("serializing match result");
+        let me = nostr.keys.public_key();
+        let opponent = self.players.iter().find(|player| **player != me)?;
+        let mut tags = vec![Tag::Generic(TagKind::P, vec![opponent.to_string()])];
+        if let Some(proposal) = proposal {
+            tags.push(Tag::Generic(TagKind::E, vec![proposal.to_hex()]));
+        }
+        EventBuilder::new(Kind::from(RESULT_KIND), content, &tags)
+            .to_event(&nostr.keys)
+            .ok()
+    }
+}
+
+/// The event a countersignature points at
+pub fn proposal_id(event: &Event) -> Option<EventId> {
+    event
+        .tags
+        .iter()
+        .map(|tag| tag.as_vec())
+        .find(|tag| tag.len() >= 2 && tag[0] == "e")
+        .and_then(|tag| EventId::from_str(&tag[1]).ok())
+}
+
+/// The result in `event`, if it was signed by `signer`
+pub fn parse_result(event: &Event, signer: XOnlyPublicKey) -> Option<MatchResult> {
+    if event.kind != Kind::from(RESULT_KIND) || event.pubkey != signer || event.verify().is_err() {
+        return None;
+    }
+    serde_json::from_str(&event.content).ok()
+}
+
+/// The result in a first signature, if whoever should propose it did
+pub fn parse_proposal(proposal: &Event) -> Option<MatchResult> {
+    let result = parse_result(proposal, proposal.pubkey)?;
+    let valid = result.proposer() == proposal.pubkey
+        && result.players.contains(&proposal.pubkey)
+        && proposal_id(proposal).is_none();
+    valid.then_some(result)
+}
+
+/// Whether `countersign` confirms `proposal`: the other player signed the same
+/// result and points at the first event
+pub fn confirms(proposal: &Event, countersign: &Event) -> Option<MatchResult> {
+    let result = parse_proposal(proposal)?;
+    let other = result
+        .players
+        .into_iter()
+        .find(|player| *player != proposal.pubkey)?;
+    let confirmed = parse_result(countersign, other)?;
+    (confirmed == result && proposal_id(countersign) == Some(proposal.id)).then_some(result)
+}
+
+/// Set once the simulation settled on a winner
+#[derive(Resource, Debug)]
+pub struct MatchOver {
+    pub result: MatchResult,
+    /// How long the match ran, in simulation frames
+    pub frames: u32,
+    /// Everyone's stats by handle at the confirmed end
+    pub stats: [PlayerStats; 2],
+    proposal: Option<Event>,
+    countersign: Option<Event>,
+    published: bool,
+    /// We asked for a rematch
+    pub rematch: bool,
+    pub opponent_rematch: bool,
+    /// When both of us had asked, see `rematch`
+    pub agreed: Option<Instant>,
+    pub opponent_left: bool,
+}
+
+impl MatchOver {
+    pub fn signed(&self) -> bool {
+        self.published
+    }
+}
+
+/// Waits until GGRS confirmed the frame one side went down on, a rollback
+/// could still undo it before that
+pub fn detect_match_end(
+    mut commands: Commands,
+    session: Res<Session<GgrsConfig>>,
+    players: Query<(&Player, &Health, &PlayerStats)>,
+    keys: Res<PlayerKeys>,
+    frame_count: Res<FrameCount>,
+    fallen: Res<FallenStats>,
+    mut pending: Local<Option<i32>>,
+) {
+    let Session::P2PSession(session) = session.as_ref() else {
+        return;
+    };
+    let alive: Vec<usize> = players
+        .iter()
+        .filter(|(_, health, _)| health.current > 0)
+        .map(|(player, _, _)| player.handle)
+        .collect();
+    if alive.len() > 1 {
+        *pending = None;
+        return;
+    }
+    let frame = *pending.get_or_insert(session.current_frame());
+    if session.confirmed_frame() < frame {
+        return;
+    }
+
+    let (Some(first), Some(second)) = (keys.0.first(), keys.0.get(1)) else {
+        return;
+    };
+    let result = MatchResult {
+        players: [*first, *second],
+        winner: alive
+            .first()
+            .and_then(|handle| keys.0.get(*handle))
+            .copied(),
+        version: GAME_VERSION.to_string(),
+    };
+    info!("match over: {result:?}");
+    // nothing left to rejoin
+    ActiveMatch::clear();
+    commands.remove_resource::<ActiveMatch>();
+    commands.insert_resource(MatchOver {
+        result,
+        frames: frame_count.0,
+        stats: stats_by_handle(
+            players.iter().map(|(player, _, stats)| (player, stats)),
+            &fallen,
+        ),
+        proposal: None,
+        countersign: None,
+        published: false,
+        rematch: false,
+        opponent_rematch: false,
+        agreed: None,
+        opponent_left: false,
+    });
+    *pending = None;
+}
+
+fn publish_results(nostr: &Nostr, events: Vec<Event>) {
+    let keys = nostr.keys.clone();
+    let relays = nostr.relays.clone();
+    spawn(async move {
+        let client = relays::connect(&keys, &relays).await;
+        for event in events {
+            if let Err(e) = client.send_msg(ClientMessage::new_event(event)).await {
+                warn!("failed to publish the match result: {e}");
+            }
+        }
+        client.disconnect().await.ok();
+    });
+}
+
+/// Trades signatures with the opponent. The proposal goes out right away, the
+/// countersignature once we have it. Also keeps track of whether they want a
+/// rematch or already left.
+pub fn exchange_result(
+    mut over: ResMut<MatchOver>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    session: Option<Res<Session<GgrsConfig>>>,
+    nostr_query: Query<&Nostr>,
+    local: Res<LocalPlayerHandle>,
+) {
+    let nostr = nostr_query.single();
+    let opponent = over.result.players[1 - local.0];
+    let peer = PeerId(opponent);
+
+    // without a session nobody else looks at the connection. GGRS timing out
+    // doesn't count, that's just the opponent having dropped their session.
+    if session.is_none() {
+        socket.update_peers();
+    }
+    if !socket.connected_peers().any(|connected| connected == peer) {
+        over.opponent_left = true;
+    }
+
+    let proposing = over.result.proposer() == over.result.players[local.0];
+    if proposing && over.proposal.is_none() {
+        over.proposal = over.result.to_event(nostr, None);
+        if let Some(proposal) = &over.proposal {
+            send_lobby_message(
+                &mut socket,
+                peer,
+                &LobbyMessage::Result(Box::new(proposal.clone())),
+            );
+            // out there even if the opponent never signs
+            publish_results(nostr, vec![proposal.clone()]);
+        }
+    }
+
+    for (from, message) in receive_lobby_messages(&mut socket) {
+        if from != peer {
+            continue;
+        }
+        let event = match message {
+            LobbyMessage::Result(event) => event,
+            LobbyMessage::Rematch(rematch) => {
+                over.opponent_rematch = rematch;
+                continue;
+            }
+            _ => continue,
+        };
+        match (&over.proposal, &over.countersign) {
+            // we're second, sign what we agree with
+            (None, _) => {
+                if parse_result(&event, opponent).as_ref() != Some(&over.result) {
+                    warn!("opponent sent a result we don't agree with");
+                    continue;
+                }
+                over.countersign = over.result.to_event(nostr, Some(event.id));
+                over.proposal = Some(*event);
+                if let Some(countersign) = &over.countersign {
+                    send_lobby_message(
+                        &mut socket,
+                        peer,
+                        &LobbyMessage::Result(Box::new(countersign.clone())),
+                    );
+                }
+            }
+            (Some(proposal), None) => {
+                if confirms(proposal, &event).is_some() {
+                    over.countersign = Some(*event);
+                } else {
+                    warn!("opponent didn't confirm our result");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let (Some(proposal), Some(countersign), false) =
+        (&over.proposal, &over.countersign, over.published)
+    {
+        publish_results(nostr, vec![proposal.clone(), countersign.clone()]);
+        over.published = true;
+    }
+}
+
+pub fn match_over_panel(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut over: ResMut<MatchOver>,
+    mut socket: Option<ResMut<MatchboxSocket<MultipleChannels>>>,
+    nostr_query: Query<&Nostr>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let me = nostr_query.single().keys.public_key();
+    let opponent = over
+        .result
+        .players
+        .iter()
+        .copied()
+        .find(|player| *player != me);
+    egui::Window::new("Match over")
+        .resizable(false)
+        .collapsible(false)
+        .anchor(egui::Align2::CENTER_TOP, egui::Vec2::new(0.0, 16.0))
+        .show(contexts.ctx_mut(), |ui| {
+            match over.result.winner {
+                Some(winner) if winner == me => ui.heading("You win!"),
+                Some(_) => ui.heading("You lose"),
+                None => ui.heading("Draw"),
+            };
+            if over.signed() {
+                ui.weak("Result signed by both players and published");
+            } else {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.weak("Signing the result with your opponent...");
+                });
+            }
+
+            ui.separator();
+            if over.opponent_left {
+                ui.label("Your opponent left.");
+            } else if over.agreed.is_some() {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Starting the rematch...");
+                });
+            } else if over.rematch {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Waiting for your opponent...");
+                });
+            } else if over.opponent_rematch {
+                ui.label("Your opponent wants a rematch!");
+            }
+
+            ui.horizontal(|ui| {
+                // the result has to be out before the next match replaces it
+                let can_rematch =
+                    over.signed() && !over.rematch && !over.opponent_left && socket.is_some();
+                if ui
+                    .add_enabled(can_rematch, egui::Button::new("Rematch"))
+                    .on_disabled_hover_text("Once the result is signed")
+                    .clicked()
+                {
+                    if let (Some(socket), Some(opponent)) = (socket.as_mut(), opponent) {
+                        send_lobby_message(socket, PeerId(opponent), &LobbyMessage::Rematch(true));
+                        over.rematch = true;
+                        // stop sending inputs for the old match, the new session
+                        // starts from frame 0 on both sides
+                        commands.remove_resource::<Session<GgrsConfig>>();
+                    }
+                }
+                if ui.button("Leave").clicked() {
+                    if let (Some(socket), Some(opponent)) = (socket.as_mut(), opponent) {
+                        send_lobby_message(socket, PeerId(opponent), &LobbyMessage::Rematch(false));
+                    }
+                    next_state.set(GameState::Menu);
+                }
+            });
+        });
+}
+
+pub fn forget_match_over(mut commands: Commands) {
+    commands.remove_resource::<MatchOver>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::Keys;
+
+    fn nostr(keys: &Keys) -> Nostr {
+        Nostr {
+            keys: keys.clone(),
+            relays: Vec::new(),
+            relay: String::new(),
+        }
+    }
+
+    #[test]
+    fn both_signatures_confirm() {
+        let (first, second) = (Keys::generate(), Keys::generate());
+        let result = MatchResult {
+            players: [first.public_key(), second.public_key()],
+            winner: Some(second.public_key()),
+            version: GAME_VERSION.to_string(),
+        };
+        let proposal = result.to_event(&nostr(&second), None).unwrap();
+        let countersign = result.to_event(&nostr(&first), Some(proposal.id)).unwrap();
+        assert_eq!(confirms(&proposal, &countersign), Some(result.clone()));
+
+        // the winner can't confirm on the loser's behalf
+        let forged = result.to_event(&nostr(&second), Some(proposal.id)).unwrap();
+        assert_eq!(confirms(&proposal, &forged), None);
+
+        // nor change the outcome after the fact
+        let changed = MatchResult {
+            winner: Some(first.public_key()),
+            ..result.clone()
+        };
+        let countersign = changed.to_event(&nostr(&first), Some(proposal.id)).unwrap();
+        assert_eq!(confirms(&proposal, &countersign), None);
+
+        // and the loser doesn't get to propose
+        let proposal = result.to_event(&nostr(&first), None).unwrap();
+        assert_eq!(parse_proposal(&proposal), None);
+    }
+}
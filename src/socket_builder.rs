@@ -0,0 +1,22 @@
+//! The channels every socket opens, in this order. Both ends of a connection
+//! have to agree on them, the handshake fails otherwise.
+//!
+//! Shared by the game and the `game_server` binary.
+
+use bevy_matchbox_nostr::prelude::*;
+use nostr_sdk::Keys;
+
+/// Channel GGRS takes over once the match starts
+pub const GGRS_CHANNEL: usize = 0;
+/// Reliable channel for everything that isn't game input
+pub const LOBBY_CHANNEL: usize = 1;
+/// Reliable too, see `chat`
+pub const CHAT_CHANNEL: usize = 2;
+
+/// A socket signalling through `relay` as `keys`
+pub fn socket_builder(relay: String, keys: Keys) -> WebRtcSocketBuilder<MultipleChannels> {
+    WebRtcSocketBuilder::new(relay, keys)
+        .add_channel(ChannelConfig::ggrs())
+        .add_channel(ChannelConfig::reliable())
+        .add_channel(ChannelConfig::reliable())
+}
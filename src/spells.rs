@@ -1,69 +1,102 @@
-use bevy::{math::Vec3Swizzles, prelude::*};
+use bevy::prelude::*;
 use bevy_ggrs::{PlayerInputs, RollbackIdProvider};
 
 use crate::{
-    components::{Bullet, BulletReady, Health, MoveDir, Player},
-    input::fire,
+    components::{
+        Bullet, BulletReady, FallenStats, Health, MoveDir, Player, PlayerStats, Position,
+    },
+    fixed::{Fixed, FixedVec2},
+    input::{fire, player_input},
     GgrsConfig, ImageAssets,
 };
 
-const PLAYER_RADIUS: f32 = 0.5;
-const BULLET_RADIUS: f32 = 0.025;
+const PLAYER_RADIUS: Fixed = Fixed::from_f32(0.5);
+const BULLET_RADIUS: Fixed = Fixed::from_f32(0.025);
 pub const BULLET_SPEED: f32 = 0.1;
 
 pub fn fire_bullets(
     mut commands: Commands,
     inputs: Res<PlayerInputs<GgrsConfig>>,
     images: Res<ImageAssets>,
-    mut player_query: Query<(&mut Transform, &mut Player, &mut BulletReady, &mut MoveDir)>,
+    mut player_query: Query<(
+        &Position,
+        &mut Player,
+        &mut BulletReady,
+        &mut MoveDir,
+        &mut PlayerStats,
+    )>,
     mut rip: ResMut<RollbackIdProvider>,
 ) {
-    for (mut transform, player, mut bullet, mut move_dir) in player_query.iter_mut() {
-        let (input, _) = inputs[player.handle];
+    for (position, player, mut bullet, mut move_dir, mut stats) in player_query.iter_mut() {
+        let input = player_input(&inputs, player.handle);
 
         if fire(input) && bullet.ready {
-            let mouse_position = Vec2::new(input.target_x, input.target_y);
-            let player_pos = transform.translation.xy();
-            let direction_to_mouse = (mouse_position - player_pos).normalize();
-            let pos = player_pos + direction_to_mouse * PLAYER_RADIUS + BULLET_RADIUS;
-            if direction_to_mouse.x > 0.0 {
-                move_dir.0 = Vec2::X;
-                transform.rotation = Quat::from_rotation_y(std::f32::consts::PI);
+            let mouse_position = input.fixed_target();
+            let player_pos = position.0;
+            let mut direction_to_mouse = (mouse_position - player_pos).normalize_or_zero();
+            if direction_to_mouse == FixedVec2::ZERO {
+                // clicked right on ourselves, shoot the way we're facing
+                direction_to_mouse = move_dir.0;
+            }
+            let pos = player_pos
+                + direction_to_mouse * PLAYER_RADIUS
+                + FixedVec2::new(BULLET_RADIUS, BULLET_RADIUS);
+            if direction_to_mouse.x > Fixed::ZERO {
+                move_dir.0 = FixedVec2::X;
             } else {
-                move_dir.0 = -Vec2::X;
-                transform.rotation = Quat::from_rotation_y(0.0);
+                move_dir.0 = FixedVec2::NEG_X;
             }
-            commands.spawn((
+            spawn_bullet(
+                &mut commands,
+                &mut rip,
+                &images,
                 Bullet {
                     shooter: player.handle,
-                    traveled: 0.0,
+                    traveled: Fixed::ZERO,
                     despawned: false,
                     hit: false,
                 },
-                rip.next(),
-                MoveDir(direction_to_mouse),
-                SpriteBundle {
-                    transform: Transform::from_translation(pos.extend(500.))
-                        .with_rotation(Quat::from_rotation_arc_2d(Vec2::X, direction_to_mouse)),
-                    texture: images.bullet.clone(),
-                    sprite: Sprite {
-                        custom_size: Some(Vec2::new(0.3, 0.3)),
-                        ..default()
-                    },
-                    ..default()
-                },
-            ));
+                pos,
+                direction_to_mouse,
+            );
             bullet.ready = false;
+            stats.shots += 1;
         }
     }
 }
 
+pub fn spawn_bullet(
+    commands: &mut Commands,
+    rip: &mut RollbackIdProvider,
+    images: &ImageAssets,
+    bullet: Bullet,
+    pos: FixedVec2,
+    direction: FixedVec2,
+) {
+    commands.spawn((
+        bullet,
+        rip.next(),
+        MoveDir(direction),
+        Position(pos),
+        SpriteBundle {
+            transform: Transform::from_translation(pos.to_vec2().extend(500.))
+                .with_rotation(Quat::from_rotation_arc_2d(Vec2::X, direction.to_vec2())),
+            texture: images.bullet.clone(),
+            sprite: Sprite {
+                custom_size: Some(Vec2::new(0.3, 0.3)),
+                ..default()
+            },
+            ..default()
+        },
+    ));
+}
+
 pub fn reload_bullet(
     inputs: Res<PlayerInputs<GgrsConfig>>,
     mut query: Query<(&mut BulletReady, &Player)>,
 ) {
     for (mut can_fire, player) in query.iter_mut() {
-        let (input, _) = inputs[player.handle];
+        let input = player_input(&inputs, player.handle);
 
         if !fire(input) {
             can_fire.ready = true;
@@ -73,15 +106,15 @@ pub fn reload_bullet(
 
 pub fn move_bullet(
     mut commands: Commands,
-    mut query: Query<(Entity, &mut Transform, &MoveDir, &mut Bullet)>,
+    mut query: Query<(Entity, &mut Position, &MoveDir, &mut Bullet)>,
 ) {
-    for (bullet, mut transform, dir, mut bullet_info) in query.iter_mut() {
-        if bullet_info.traveled <= 5.0 {
+    for (bullet, mut position, dir, mut bullet_info) in query.iter_mut() {
+        if bullet_info.traveled <= Fixed::from_int(5) {
             // let delta = (dir.0 * BULLET_SPEED).extend(0.);
-            transform.translation += (dir.0).extend(0.);
+            position.0 += dir.0;
 
             // Update the traveled distance
-            bullet_info.traveled += 0.3;
+            bullet_info.traveled += Fixed::from_f32(0.3);
         } else {
             bullet_info.hit = false;
             bullet_info.despawned = true;
@@ -92,15 +125,12 @@ pub fn move_bullet(
 
 pub fn kill_players(
     mut commands: Commands,
-    mut player_query: Query<(&Transform, &Player, &Health)>,
-    mut bullet_query: Query<(Entity, &Transform, &mut Bullet)>,
+    mut player_query: Query<(&Position, &Player, &Health)>,
+    mut bullet_query: Query<(Entity, &Position, &mut Bullet)>,
 ) {
-    for (player_transform, player_info, health) in player_query.iter_mut() {
-        for (bullet, bullet_transform, mut bullet_info) in bullet_query.iter_mut() {
-            let distance = Vec2::distance(
-                player_transform.translation.xy(),
-                bullet_transform.translation.xy(),
-            );
+    for (player_position, player_info, health) in player_query.iter_mut() {
+        for (bullet, bullet_position, mut bullet_info) in bullet_query.iter_mut() {
+            let distance = player_position.0.distance(bullet_position.0);
 
             if distance < PLAYER_RADIUS + BULLET_RADIUS && bullet_info.shooter != player_info.handle
             {
@@ -113,23 +143,121 @@ pub fn kill_players(
 }
 
 pub fn update_health(
-    mut player_query: Query<(Entity, &Player, &mut Health)>,
+    mut player_query: Query<(Entity, &Player, &mut Health, &mut PlayerStats)>,
     bullet_query: Query<&mut Bullet>,
+    mut fallen: ResMut<FallenStats>,
     mut commands: Commands,
 ) {
-    for (player, player_info, mut health) in player_query.iter_mut() {
+    for (player, player_info, mut health, mut stats) in player_query.iter_mut() {
         if health.current == 0 {
             commands.entity(player).despawn();
             info!("Player {} died", player_info.handle);
+            // the match result still needs them
+            if let Some(slot) = fallen.0.get_mut(player_info.handle) {
+                *slot = *stats;
+            }
+            continue;
         }
         for bullet in bullet_query.iter() {
             if bullet.shooter != player_info.handle {
                 if bullet.despawned && bullet.hit {
                     health.current -= 1;
+                    stats.damage_taken += 1;
                 } else if bullet.despawned && !bullet.hit && health.current != health.max {
                     health.current += 1;
+                    stats.healed += 1;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::any::TypeId;
+
+    use bevy::ecs::reflect::ReflectComponent;
+    use bevy::reflect::{FromType, GetTypeRegistration, TypeRegistry};
+
+    use super::*;
+    use crate::components::Target;
+
+    /// Same as `register_rollback_component` does for GGRS
+    fn register<T: Component + Reflect + GetTypeRegistration>(registry: &mut TypeRegistry) {
+        registry.register::<T>();
+        registry
+            .get_mut(TypeId::of::<T>())
+            .unwrap()
+            .insert(<ReflectComponent as FromType<T>>::from_type());
+    }
+
+    /// What GGRS saves of `entity` for a rollback
+    fn save(registry: &TypeRegistry, world: &World, entity: Entity) -> Vec<Box<dyn Reflect>> {
+        registry
+            .iter()
+            .filter_map(|registration| registration.data::<ReflectComponent>())
+            .filter_map(|component| component.reflect(world.entity(entity)))
+            .map(|component| component.clone_value())
+            .collect()
+    }
+
+    fn load(
+        registry: &TypeRegistry,
+        world: &mut World,
+        entity: Entity,
+        saved: &[Box<dyn Reflect>],
+    ) {
+        for component in saved {
+            let registration = registry.get_with_name(component.type_name()).unwrap();
+            let reflect = registration.data::<ReflectComponent>().unwrap();
+            reflect.apply(&mut world.entity_mut(entity), &**component);
+        }
+    }
+
+    #[test]
+    fn rolling_back_undoes_a_hit() {
+        // what main registers with `GGRSPlugin`
+        let mut registry = TypeRegistry::default();
+        register::<Position>(&mut registry);
+        register::<Target>(&mut registry);
+        register::<BulletReady>(&mut registry);
+        register::<MoveDir>(&mut registry);
+        register::<Bullet>(&mut registry);
+        register::<Player>(&mut registry);
+        register::<Health>(&mut registry);
+        register::<PlayerStats>(&mut registry);
+
+        let mut world = World::new();
+        world.init_resource::<FallenStats>();
+        let player = world
+            .spawn((
+                Player {
+                    handle: 0,
+                    moving: true,
+                },
+                Target::default(),
+                Health { current: 3, max: 3 },
+                PlayerStats::default(),
+            ))
+            .id();
+        world.spawn(Bullet {
+            shooter: 1,
+            traveled: Fixed::ZERO,
+            despawned: true,
+            hit: true,
+        });
+        let saved = save(&registry, &world, player);
+
+        // a mispredicted frame: hit while standing still
+        world.get_mut::<Player>(player).unwrap().moving = false;
+        let mut schedule = Schedule::new();
+        schedule.add_system(update_health);
+        schedule.run(&mut world);
+        assert_eq!(world.get::<Health>(player).unwrap().current, 2);
+
+        load(&registry, &mut world, player, &saved);
+        assert_eq!(world.get::<Health>(player).unwrap().current, 3);
+        assert_eq!(world.get::<PlayerStats>(player).unwrap().damage_taken, 0);
+        assert!(world.get::<Player>(player).unwrap().moving);
+    }
+}
@@ -0,0 +1,28 @@
+//! Where the async Nostr work runs. In the browser that's the page's own event
+//! loop, natively a small tokio runtime in the background, which nostr-sdk
+//! needs there anyway.
+
+use std::future::Future;
+
+#[cfg(target_arch = "wasm32")]
+pub fn spawn(task: impl Future<Output = ()> + 'static) {
+    wasm_bindgen_futures::spawn_local(task);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn(task: impl Future<Output = ()> + Send + 'static) {
+    runtime().spawn(task);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("nostr")
+            .enable_all()
+            .build()
+            .expect("starting the async runtime")
+    })
+}
@@ -0,0 +1,103 @@
+use bevy::prelude::*;
+use bevy_ggrs::Session;
+
+use crate::{network::FPS, GgrsConfig};
+
+/// Keeps both peers' clocks aligned so the faster machine doesn't keep running
+/// ahead and forcing the other one into rollbacks.
+///
+/// Running a frame or two ahead is left to `GGRSPlugin`, it already steps a
+/// little slower while `frames_ahead` is positive. `WaitRecommendation`s are
+/// bigger gaps, GGRS sits those frames out entirely, see `hold_session`. `Time`
+/// is left alone, so rendering, smoothing and UI timers never slow down.
+#[derive(Resource, Debug, Default)]
+pub struct TimeSync {
+    /// GGRS frames still to sit out from the last `WaitRecommendation`
+    skip: f32,
+    /// How far ahead of the other peer we are, for the overlay
+    pub frames_ahead: i32,
+}
+
+impl TimeSync {
+    /// GGRS recommended waiting, sit out that many frames
+    pub fn skip(&mut self, frames: u32) {
+        self.skip = self.skip.max(frames as f32);
+    }
+
+    pub fn skipping(&self) -> bool {
+        self.skip > 0.0
+    }
+
+    /// Whether GGRS sits out this update. Counted in GGRS frames, however many
+    /// updates that takes at the current frame rate.
+    fn hold(&mut self, delta_seconds: f32) -> bool {
+        if !self.skipping() {
+            return false;
+        }
+        self.skip -= delta_seconds * FPS as f32;
+        true
+    }
+}
+
+/// The session while GGRS sits out an update
+#[derive(Resource)]
+pub struct HeldSession(Session<GgrsConfig>);
+
+pub fn time_sync(mut sync: ResMut<TimeSync>, session: Res<Session<GgrsConfig>>) {
+    sync.frames_ahead = match session.as_ref() {
+        Session::P2PSession(s) => s.frames_ahead(),
+        _ => 0,
+    };
+}
+
+/// Runs before `GGRSPlugin` advances the session in `PreUpdate`. It can't
+/// advance a session that isn't there, `release_session` puts it back right
+/// after.
+pub fn hold_session(world: &mut World) {
+    let delta = world.resource::<Time>().delta_seconds();
+    if !world.resource_mut::<TimeSync>().hold(delta) {
+        return;
+    }
+    let Some(mut session) = world.remove_resource::<Session<GgrsConfig>>() else {
+        return;
+    };
+    // GGRS doesn't poll it either, the connection still needs that
+    if let Session::P2PSession(s) = &mut session {
+        s.poll_remote_clients();
+    }
+    world.insert_resource(HeldSession(session));
+}
+
+pub fn release_session(world: &mut World) {
+    if let Some(HeldSession(session)) = world.remove_resource::<HeldSession>() {
+        world.insert_resource(session);
+    }
+}
+
+pub fn reset_time_sync(mut sync: ResMut<TimeSync>) {
+    *sync = TimeSync::default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_frames_not_updates() {
+        let frame = 1.0 / FPS as f32;
+
+        // at 144Hz three frames take a bit more than seven updates
+        let mut sync = TimeSync::default();
+        sync.skip(3);
+        sync.skip(2);
+        let held = (0..20).take_while(|_| sync.hold(1.0 / 144.0)).count();
+        assert_eq!(held, 8);
+        assert!(!sync.skipping());
+
+        // at 30Hz a single update covers two
+        let mut sync = TimeSync::default();
+        sync.skip(3);
+        let held = (0..20).take_while(|_| sync.hold(2.0 * frame)).count();
+        assert_eq!(held, 2);
+    }
+}